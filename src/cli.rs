@@ -1,5 +1,12 @@
+use semver::VersionReq;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
+use crate::repos::{FailurePolicy, QueuePolicy};
+use crate::verify::Verify;
+
+// Parsed once at start up, so the size of the Spy variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
 pub enum Action {
     /// Spy a remote git repo for changes, will continuously execute defined script/command on a diff
@@ -7,9 +14,57 @@ pub enum Action {
         /// The remote git repo to watch for changes
         #[structopt()]
         url: String,
-        /// The branch of the remote git repo to watch for changes
-        #[structopt(short, long, default_value = "main")]
-        branch: String,
+        /// The branch, or glob of branches, of the remote git repo to watch for changes, repeat to
+        /// watch several [default: main]
+        #[structopt(short, long, number_of_values = 1)]
+        branch: Vec<String>,
+        /// Watch for new tags matching this glob (e.g. 'v*'), checking out the newest
+        #[structopt(long)]
+        tag: Option<String>,
+        /// Only fire for tags whose version is in this semver range (e.g. '>=2.0, <3')
+        #[structopt(long, requires = "tag")]
+        semver: Option<VersionReq>,
+        /// Only fire when a changed path matches this glob (e.g. 'services/api/**'), repeatable
+        #[structopt(long, number_of_values = 1)]
+        include: Vec<String>,
+        /// Never fire for changed paths matching this glob (e.g. '**/*.md'), repeatable
+        #[structopt(long, number_of_values = 1)]
+        exclude: Vec<String>,
+        /// Ignore commits by this author, a name or email glob (e.g. '*@bots.example.com'), repeatable
+        #[structopt(long, number_of_values = 1)]
+        ignore_author: Vec<String>,
+        /// Only fire for commits with this trailer (e.g. 'Deploy: production'), repeatable
+        #[structopt(long, number_of_values = 1)]
+        require_trailer: Vec<String>,
+        /// Which new commits must be signed by a trusted key before being merged and run: off, tip
+        /// or all
+        #[structopt(long, default_value = "off")]
+        verify_signatures: Verify,
+        /// The GPG keyring of keys trusted to sign commits
+        #[structopt(long)]
+        gpg_keyring: Option<PathBuf>,
+        /// The SSH allowed signers file of keys trusted to sign commits
+        #[structopt(long)]
+        allowed_signers: Option<PathBuf>,
+        /// Run the .goa file of each changed subdirectory, from within that subdirectory
+        #[structopt(short, long)]
+        monorepo: bool,
+        /// Run the command once for each new commit on a branch, oldest first, stopping at the
+        /// first to fail
+        #[structopt(long)]
+        per_commit: bool,
+        /// Only clone and fetch this many commits of history, needs the git command line
+        #[structopt(long)]
+        depth: Option<u32>,
+        /// Only clone and fetch the spied branches
+        #[structopt(long)]
+        single_branch: bool,
+        /// Only check out paths matching this pattern (e.g. 'deploy/site-a'), repeatable
+        #[structopt(long, number_of_values = 1)]
+        sparse: Vec<String>,
+        /// Initialize and update submodules, recursively
+        #[structopt(long)]
+        submodules: bool,
         /// The time between checks in seconds, max 65535
         #[structopt(short, long, default_value = "120")]
         delay: u16,
@@ -22,6 +77,37 @@ pub enum Action {
         /// The command to run when a change is detected
         #[structopt(short, long, default_value = "")]
         command: String,
+        /// A command to run after the command succeeds
+        #[structopt(long, default_value = "")]
+        on_success: String,
+        /// A command to run after the command fails
+        #[structopt(long, default_value = "")]
+        on_failure: String,
+        /// A command to run after the command, whether it succeeds or fails
+        #[structopt(long, default_value = "")]
+        always: String,
+        /// Kill the command, and everything it started, after this many seconds
+        #[structopt(long)]
+        timeout: Option<u64>,
+        /// What to do when the command fails: continue, exit (with its exit code) or pause
+        #[structopt(long, default_value = "continue")]
+        failure_policy: FailurePolicy,
+        /// Run a failed command again, up to this many times
+        #[structopt(long, default_value = "0")]
+        retries: u32,
+        /// The seconds to wait before the first retry
+        #[structopt(long, default_value = "10")]
+        retry_delay: f64,
+        /// What the wait is multiplied by for each further retry
+        #[structopt(long, default_value = "2")]
+        retry_backoff: f64,
+        /// The fraction of each wait randomly added or taken away, from 0 to 1
+        #[structopt(long, default_value = "0")]
+        retry_jitter: f64,
+        /// What to do with new commits that land while the command runs: queue (run again for the
+        /// latest once it finishes), cancel (stop it and run again) or skip (merge without running)
+        #[structopt(long, default_value = "queue")]
+        queue_policy: QueuePolicy,
         /// Adjust level of stdout, 0 no goa output , max 2 (debug)
         #[structopt(short, long, default_value = "1")]
         verbosity: u8,
@@ -34,12 +120,27 @@ pub enum Action {
         /// The target path for the clone
         #[structopt(short = "T", long)]
         target_path: Option<String>,
+        /// Where to remember the last deployed commits across restarts [default:
+        /// $XDG_STATE_HOME/goa or ~/.local/state/goa]
+        #[structopt(long)]
+        state_dir: Option<PathBuf>,
+    },
+    /// Run the agent against a config file, spying every repo it describes
+    Run {
+        /// The TOML or YAML config file describing the repos to spy
+        #[structopt(short, long)]
+        config: PathBuf,
+        /// The number of repos that may be checked, or run commands, at the same time
+        #[structopt(short, long)]
+        workers: Option<usize>,
+        /// Adjust level of stdout, 0 no goa output , max 2 (debug)
+        #[structopt(short, long, default_value = "1")]
+        verbosity: u8,
     },
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "goa", about = "A command-line GitOps utility agent")]
-
 pub struct CommandLineArgs {
     #[structopt(subcommand)]
     pub action: Action,
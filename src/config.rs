@@ -0,0 +1,778 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use semver::VersionReq;
+use serde::Deserialize;
+
+use crate::filters::{CommitFilter, PathFilter};
+use crate::repos::{Branch, FailurePolicy, Hooks, QueuePolicy, Repo};
+use crate::retry::Retry;
+use crate::state;
+use crate::tags::TagWatch;
+use crate::verify::{TrustPolicy, Verify};
+
+/// Workers used when neither the command line nor the config sets them
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// The agent config file, describing every repo a single goa process spies
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The number of repos that may be checked, or run commands, at the same time
+    pub workers: Option<usize>,
+    /// Where the repos' last deployed commits are remembered across restarts
+    pub state_dir: Option<PathBuf>,
+    pub repos: Vec<RepoConfig>,
+}
+
+/// A single watched repo within the agent config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoConfig {
+    /// A unique name for the watcher, defaults to the url
+    pub name: Option<String>,
+    /// The remote git repo to watch for changes
+    pub url: String,
+    /// The branch of the remote git repo to watch for changes
+    pub branch: Option<String>,
+    /// Several branches to watch, sharing one clone, each with its own command
+    #[serde(default)]
+    pub branches: Vec<BranchConfig>,
+    /// New tags to watch for, alongside or instead of branches
+    pub tag: Option<TagConfig>,
+    /// Only fire when a changed path matches one of these globs
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Never fire for changed paths matching these globs
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Ignore commits by these authors, globs over the name or email
+    #[serde(default)]
+    pub ignore_authors: Vec<String>,
+    /// Only fire for commits with every one of these trailers, e.g. `Deploy: production`
+    #[serde(default)]
+    pub require_trailers: Vec<String>,
+    /// Which new commits must be signed by a trusted key: off, tip or all
+    pub verify_signatures: Option<String>,
+    /// The GPG keyring of keys trusted to sign commits
+    pub gpg_keyring: Option<PathBuf>,
+    /// The SSH allowed signers file of keys trusted to sign commits
+    pub allowed_signers: Option<PathBuf>,
+    /// Run the .goa file of each changed subdirectory instead of a command
+    #[serde(default)]
+    pub monorepo: bool,
+    /// Run the command once for each new commit, stopping at the first to fail
+    #[serde(default)]
+    pub per_commit: bool,
+    /// Commits of history to clone and fetch, all of it when unset
+    pub depth: Option<u32>,
+    /// Clone and fetch only the watched branches
+    #[serde(default)]
+    pub single_branch: bool,
+    /// The only paths checked out, e.g. `deploy/site-a`, all of them when empty
+    #[serde(default)]
+    pub sparse: Vec<String>,
+    /// Initialize and update submodules, recursively
+    #[serde(default)]
+    pub submodules: bool,
+    /// The time between checks in seconds
+    #[serde(default = "default_delay")]
+    pub delay: u16,
+    /// The command to run when a change is detected, empty uses the .goa file
+    #[serde(default)]
+    pub command: String,
+    /// A command to run after the command succeeds
+    #[serde(default)]
+    pub on_success: String,
+    /// A command to run after the command fails
+    #[serde(default)]
+    pub on_failure: String,
+    /// A command to run after the command, whether it succeeds or fails
+    #[serde(default)]
+    pub always: String,
+    /// Seconds a command may run before it's killed
+    pub timeout: Option<u64>,
+    /// What to do when a command fails: continue, exit or pause
+    pub failure_policy: Option<String>,
+    /// How failed commands are run again
+    pub retry: Option<RetryConfig>,
+    /// What to do with new commits that land while a command runs: queue,
+    /// cancel or skip
+    pub queue_policy: Option<String>,
+    /// Username, owner of the token - required for private repos
+    pub username: Option<String>,
+    /// Name of the environment variable holding the access token
+    pub token_env: Option<String>,
+    /// Path to a file holding the access token
+    pub token_file: Option<String>,
+    /// The target path for the clone
+    pub target_path: Option<String>,
+    /// Execute the command, or .goa file, on start
+    #[serde(default)]
+    pub exec_on_start: bool,
+}
+
+/// A branch watched alongside others in the same repo
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BranchConfig {
+    pub name: String,
+    /// The command to run when this branch changes, defaults to the repo's
+    #[serde(default)]
+    pub command: String,
+}
+
+/// How a repo's failed commands are run again, any field left out keeps its
+/// default
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Attempts after the first
+    pub count: u32,
+    /// Seconds before the first retry
+    pub delay: Option<f64>,
+    /// What each wait is multiplied by for the next retry
+    pub backoff: Option<f64>,
+    /// The fraction of each wait randomly added or taken away
+    pub jitter: Option<f64>,
+}
+
+/// Tags watched for in a repo
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TagConfig {
+    /// A glob such as `v*`
+    pub pattern: String,
+    /// Only fire for tags whose version is in this range, e.g. `>=2.0, <3`
+    pub semver: Option<String>,
+    /// The command to run for a new tag, defaults to the repo's
+    #[serde(default)]
+    pub command: String,
+}
+
+const DEFAULT_BRANCH: &str = "main";
+
+fn has_command(repo: &Repo) -> bool {
+    !repo.command.is_empty()
+        || repo.branches.iter().any(|b| !b.command.is_empty())
+        || repo.tag.as_ref().is_some_and(|t| !t.command.is_empty())
+}
+
+fn default_delay() -> u16 {
+    120
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Read a config file, YAML when the extension says so and TOML otherwise
+pub fn load(path: &Path) -> Result<Config> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("unable to read config {}, {}", path.display(), e),
+        )
+    })?;
+    let yaml = matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml") | Some("yml")
+    );
+    parse(&contents, yaml)
+}
+
+pub fn parse(contents: &str, yaml: bool) -> Result<Config> {
+    if yaml {
+        serde_yaml::from_str(contents).map_err(|e| invalid(format!("invalid config, {}", e)))
+    } else {
+        toml::from_str(contents).map_err(|e| invalid(format!("invalid config, {}", e)))
+    }
+}
+
+impl Config {
+    pub fn into_repos(self, verbosity: u8) -> Result<Vec<Repo>> {
+        if self.repos.is_empty() {
+            return Err(invalid(String::from("config has no repos to spy")));
+        }
+
+        let mut names = HashSet::new();
+        let mut target_paths = HashSet::new();
+        let mut repos = Vec::with_capacity(self.repos.len());
+        for repo_config in &self.repos {
+            let name = repo_config.name();
+            if !names.insert(name.clone()) {
+                return Err(invalid(format!("duplicate repo name {}", name)));
+            }
+            if let Some(ref target_path) = repo_config.target_path {
+                if !target_paths.insert(target_path.clone()) {
+                    return Err(invalid(format!("duplicate target_path {}", target_path)));
+                }
+            }
+            let mut repo = repo_config.to_repo(verbosity)?;
+            repo.state_dir = self.state_dir.clone().or_else(state::default_dir);
+            repos.push(repo);
+        }
+        Ok(repos)
+    }
+}
+
+impl RepoConfig {
+    pub fn name(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => format!("{}:{}", self.url, self.branch_names()),
+        }
+    }
+
+    fn branch_names(&self) -> String {
+        match self.branch {
+            Some(ref branch) => branch.clone(),
+            None if self.branches.is_empty() => match self.tag {
+                Some(ref tag) => tag.pattern.clone(),
+                None => String::from(DEFAULT_BRANCH),
+            },
+            None => self
+                .branches
+                .iter()
+                .map(|b| b.name.as_str())
+                .collect::<Vec<&str>>()
+                .join(","),
+        }
+    }
+
+    fn to_branches(&self) -> Result<Vec<Branch>> {
+        if self.branch.is_some() && !self.branches.is_empty() {
+            return Err(invalid(format!(
+                "{}: only one of branch or branches may be set",
+                self.name()
+            )));
+        }
+        if self.branch.is_none() && self.branches.is_empty() && self.tag.is_some() {
+            return Ok(vec![]);
+        }
+        if self.branches.is_empty() {
+            let name = self.branch.as_deref().unwrap_or(DEFAULT_BRANCH);
+            return Ok(vec![Branch::new(name.to_string(), String::new())]);
+        }
+
+        let mut names = HashSet::new();
+        let mut branches = Vec::with_capacity(self.branches.len());
+        for branch in &self.branches {
+            if !names.insert(branch.name.as_str()) {
+                return Err(invalid(format!(
+                    "{}: branch {} is listed more than once",
+                    self.name(),
+                    branch.name
+                )));
+            }
+            branches.push(Branch::new(branch.name.clone(), branch.command.clone()));
+        }
+        Ok(branches)
+    }
+
+    /// Resolve the credentials reference into the token itself
+    pub fn token(&self) -> Result<Option<String>> {
+        match (&self.token_env, &self.token_file) {
+            (Some(_), Some(_)) => Err(invalid(format!(
+                "{}: only one of token_env or token_file may be set",
+                self.name()
+            ))),
+            (Some(var), None) => match std::env::var(var) {
+                Ok(token) => Ok(Some(token)),
+                Err(_) => Err(invalid(format!(
+                    "{}: environment variable {} is not set",
+                    self.name(),
+                    var
+                ))),
+            },
+            (None, Some(path)) => match std::fs::read_to_string(path) {
+                Ok(token) => Ok(Some(token.trim().to_string())),
+                Err(e) => Err(invalid(format!(
+                    "{}: unable to read token file {}, {}",
+                    self.name(),
+                    path,
+                    e
+                ))),
+            },
+            (None, None) => Ok(None),
+        }
+    }
+
+    pub fn to_repo(&self, verbosity: u8) -> Result<Repo> {
+        let mut repo = Repo::new(
+            self.url.clone(),
+            self.username.clone(),
+            self.token()?,
+            Some(String::from("initialize")),
+            self.target_path.clone(),
+            String::from(DEFAULT_BRANCH),
+            self.command.clone(),
+            self.delay,
+            verbosity,
+            self.exec_on_start,
+            false,
+        );
+        repo.name = self.name();
+        repo.branches = self.to_branches()?;
+        repo.tag = self.to_tag()?;
+        repo.paths = PathFilter::new(&self.include, &self.exclude)?;
+        repo.commits = CommitFilter::new(&self.ignore_authors, &self.require_trailers)?;
+        repo.trust = self.to_trust()?;
+        repo.monorepo = self.monorepo;
+        repo.per_commit = self.per_commit;
+        if self.delay == 0 {
+            return Err(invalid(format!("{}: delay must be 1 or more", repo.name)));
+        }
+        if self.depth == Some(0) {
+            return Err(invalid(format!("{}: depth must be 1 or more", repo.name)));
+        }
+        repo.depth = self.depth;
+        repo.single_branch = self.single_branch;
+        repo.sparse = self.sparse.clone();
+        repo.submodules = self.submodules;
+        repo.hooks = Hooks {
+            on_success: self.on_success.clone(),
+            on_failure: self.on_failure.clone(),
+            always: self.always.clone(),
+        };
+        repo.timeout = self.timeout;
+        repo.failure_policy = match self.failure_policy {
+            Some(ref policy) => policy
+                .parse()
+                .map_err(|e| invalid(format!("{}: failure_policy {}", repo.name, e)))?,
+            None => FailurePolicy::Continue,
+        };
+        repo.retry = self.to_retry()?;
+        repo.queue_policy = match self.queue_policy {
+            Some(ref policy) => policy
+                .parse()
+                .map_err(|e| invalid(format!("{}: queue_policy {}", repo.name, e)))?,
+            None => QueuePolicy::Queue,
+        };
+        if repo.monorepo && has_command(&repo) {
+            return Err(invalid(format!(
+                "{}: monorepo runs the .goa files, it can't be used with a command",
+                repo.name
+            )));
+        }
+        Ok(repo)
+    }
+
+    fn to_trust(&self) -> Result<TrustPolicy> {
+        let verify = match self.verify_signatures {
+            Some(ref verify) => verify
+                .parse()
+                .map_err(|e| invalid(format!("{}: verify_signatures {}", self.name(), e)))?,
+            None => Verify::Off,
+        };
+        TrustPolicy::new(
+            verify,
+            self.gpg_keyring.clone(),
+            self.allowed_signers.clone(),
+        )
+        .map_err(|e| invalid(format!("{}: {}", self.name(), e)))
+    }
+
+    fn to_retry(&self) -> Result<Retry> {
+        let retry = match self.retry {
+            Some(ref retry) => retry,
+            None => return Ok(Retry::default()),
+        };
+        let default = Retry::default();
+        Retry::new(
+            retry.count,
+            retry.delay.unwrap_or(default.delay),
+            retry.backoff.unwrap_or(default.backoff),
+            retry.jitter.unwrap_or(default.jitter),
+        )
+        .map_err(|e| invalid(format!("{}: {}", self.name(), e)))
+    }
+
+    fn to_tag(&self) -> Result<Option<TagWatch>> {
+        let tag = match self.tag {
+            Some(ref tag) => tag,
+            None => return Ok(None),
+        };
+        let semver = match tag.semver {
+            Some(ref range) => Some(VersionReq::parse(range).map_err(|e| {
+                invalid(format!(
+                    "{}: invalid semver range {}, {}",
+                    self.name(),
+                    range,
+                    e
+                ))
+            })?),
+            None => None,
+        };
+        Ok(Some(TagWatch::new(
+            tag.pattern.clone(),
+            semver,
+            tag.command.clone(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml_config() {
+        let config = parse(
+            r#"
+            workers = 2
+
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            command = "echo hello"
+
+            [[repos]]
+            name = "develop"
+            url = "https://github.com/kitplummer/goa_tester"
+            branch = "develop"
+            delay = 20
+            target_path = "/tmp/goa_develop"
+            "#,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(config.workers, Some(2));
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos.len(), 2);
+        assert_eq!(
+            repos[0].name,
+            "https://github.com/kitplummer/goa_tester:main"
+        );
+        assert_eq!(repos[0].branches[0].name, "main");
+        assert_eq!(repos[0].delay, 120);
+        assert_eq!(repos[0].command, "echo hello");
+        assert_eq!(repos[1].name, "develop");
+        assert_eq!(repos[1].delay, 20);
+        assert_eq!(repos[1].local_path, Some(String::from("/tmp/goa_develop")));
+    }
+
+    #[test]
+    fn test_parse_yaml_config() {
+        let config = parse(
+            "repos:\n  - url: https://github.com/kitplummer/goa_tester\n    branch: develop\n    exec_on_start: true\n",
+            true,
+        )
+        .unwrap();
+
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos[0].branches[0].name, "develop");
+        assert!(repos[0].exec_on_start);
+    }
+
+    #[test]
+    fn test_parse_branches() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            command = "./deploy.sh"
+
+            [[repos.branches]]
+            name = "staging"
+
+            [[repos.branches]]
+            name = "production"
+            command = "./deploy.sh --prod"
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(
+            repos[0].name,
+            "https://github.com/kitplummer/goa_tester:staging,production"
+        );
+        assert_eq!(
+            repos[0].branches,
+            vec![
+                Branch::new(String::from("staging"), String::new()),
+                Branch::new(
+                    String::from("production"),
+                    String::from("./deploy.sh --prod")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_branch_and_branches() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            branch = "main"
+
+            [[repos.branches]]
+            name = "staging"
+            "#,
+            false,
+        )
+        .unwrap();
+
+        assert!(config.into_repos(1).is_err());
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            tag = { pattern = "v*", semver = ">=2.0, <3", command = "./deploy.sh" }
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos[0].name, "https://github.com/kitplummer/goa_tester:v*");
+        assert!(repos[0].branches.is_empty());
+        let tag = repos[0].tag.as_ref().unwrap();
+        assert_eq!(tag.pattern, "v*");
+        assert!(tag.allows("v2.1.0"));
+        assert!(!tag.allows("v3.0.0"));
+        assert_eq!(tag.command, "./deploy.sh");
+    }
+
+    #[test]
+    fn test_parse_path_filters() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            include = ["services/api/**"]
+            exclude = ["**/*.md"]
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let repos = config.into_repos(1).unwrap();
+        assert!(repos[0].paths.matches("services/api/main.rs"));
+        assert!(!repos[0].paths.matches("services/api/README.md"));
+        assert!(!repos[0].paths.matches("services/web/main.rs"));
+    }
+
+    #[test]
+    fn test_monorepo_with_command() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            monorepo = true
+            branches = [{ name = "main", command = "make" }]
+            "#,
+            false,
+        )
+        .unwrap();
+        assert!(config.into_repos(1).is_err());
+    }
+
+    #[test]
+    fn test_parse_verify_signatures() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            verify_signatures = "all"
+            allowed_signers = "/etc/goa/allowed_signers"
+            "#,
+            false,
+        )
+        .unwrap();
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos[0].trust.verify, Verify::All);
+
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            verify_signatures = "tip"
+            "#,
+            false,
+        )
+        .unwrap();
+        assert!(config.into_repos(1).is_err());
+    }
+
+    #[test]
+    fn test_parse_failure_policy() {
+        let config = parse(
+            "repos:\n  - url: https://github.com/kitplummer/goa_tester\n    failure_policy: pause\n",
+            true,
+        )
+        .unwrap();
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos[0].failure_policy, FailurePolicy::Pause);
+        assert_eq!(repos[0].queue_policy, QueuePolicy::Queue);
+
+        let config = parse(
+            "repos:\n  - url: https://github.com/kitplummer/goa_tester\n    failure_policy: retry\n",
+            true,
+        )
+        .unwrap();
+        let err = config.into_repos(1).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("isn't one of continue, exit or pause"));
+    }
+
+    #[test]
+    fn test_parse_retry() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            retry = { count = 3, delay = 5, jitter = 0.2 }
+            "#,
+            false,
+        )
+        .unwrap();
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos[0].retry, Retry::new(3, 5.0, 2.0, 0.2).unwrap());
+
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            retry = { count = 3, backoff = 0.5 }
+            "#,
+            false,
+        )
+        .unwrap();
+        assert!(config.into_repos(1).is_err());
+    }
+
+    #[test]
+    fn test_parse_depth() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            depth = 1
+            single_branch = true
+            "#,
+            false,
+        )
+        .unwrap();
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos[0].depth, Some(1));
+        assert!(repos[0].single_branch);
+
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            depth = 0
+            "#,
+            false,
+        )
+        .unwrap();
+        assert!(config.into_repos(1).is_err());
+    }
+
+    #[test]
+    fn test_zero_delay() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            delay = 0
+            "#,
+            false,
+        )
+        .unwrap();
+        assert!(config.into_repos(1).is_err());
+    }
+
+    #[test]
+    fn test_invalid_semver() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            tag = { pattern = "v*", semver = "two or more" }
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let err = config.into_repos(1).unwrap_err();
+        assert!(err.to_string().contains("invalid semver range"));
+    }
+
+    #[test]
+    fn test_token_from_env() {
+        std::env::set_var("GOA_CONFIG_TEST_TOKEN", "s3cret");
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            username = "kit"
+            token_env = "GOA_CONFIG_TEST_TOKEN"
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let repos = config.into_repos(1).unwrap();
+        assert_eq!(repos[0].token, Some(String::from("s3cret")));
+    }
+
+    #[test]
+    fn test_missing_token_env() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            token_env = "GOA_CONFIG_TEST_NOT_SET"
+            "#,
+            false,
+        )
+        .unwrap();
+
+        let err = config.into_repos(1).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("GOA_CONFIG_TEST_NOT_SET is not set"));
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let config = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            "#,
+            false,
+        )
+        .unwrap();
+
+        assert!(config.into_repos(1).is_err());
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let res = parse(
+            r#"
+            [[repos]]
+            url = "https://github.com/kitplummer/goa_tester"
+            brnach = "develop"
+            "#,
+            false,
+        );
+
+        assert!(res.is_err());
+    }
+}
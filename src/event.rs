@@ -0,0 +1,155 @@
+use std::io::{Error, Result};
+use std::path::Path;
+
+use git2::{Commit, Oid, Repository};
+use serde::Serialize;
+
+use crate::git::{self, DiffStats, FileChange};
+use crate::repos::Repo;
+use crate::spy::public_url;
+
+/// The change a command runs for, as handed to it in the event file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub run_id: Option<String>,
+    pub repo_url: String,
+    pub trigger: Option<String>,
+    pub branch: Option<String>,
+    pub branch_event: Option<String>,
+    pub tag: Option<String>,
+    pub directory: Option<String>,
+    pub previous_sha: Option<String>,
+    pub sha: Option<String>,
+    /// The commits the change brings in, oldest first
+    pub commits: Vec<EventCommit>,
+    pub files: Vec<FileChange>,
+    pub stats: DiffStats,
+}
+
+/// A commit of the change
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventCommit {
+    pub id: String,
+    pub author: String,
+    pub email: String,
+    pub message: String,
+    /// RFC 3339, in the commit's own offset
+    pub time: String,
+}
+
+impl From<&Commit<'_>> for EventCommit {
+    fn from(commit: &Commit) -> EventCommit {
+        let author = commit.author();
+        EventCommit {
+            id: commit.id().to_string(),
+            author: author.name().unwrap_or_default().to_string(),
+            email: author.email().unwrap_or_default().to_string(),
+            message: commit.message().unwrap_or_default().to_string(),
+            time: git::iso_time(commit.time()),
+        }
+    }
+}
+
+/// Describe the change the command is about to run for, from the
+/// environment it's given
+pub fn describe(repo: &Repo) -> Result<Event> {
+    let local_repo = Repository::open(repo.local_path.as_ref().unwrap())
+        .map_err(|e| Error::other(e.to_string()))?;
+    describe_change(repo, &local_repo).map_err(|e| Error::other(e.to_string()))
+}
+
+fn describe_change(
+    repo: &Repo,
+    local_repo: &Repository,
+) -> std::result::Result<Event, git2::Error> {
+    let env = |name: &str| repo.envs.get(name).cloned();
+    let sha = match env("GOA_LAST_COMMIT_ID") {
+        Some(sha) => Some(Oid::from_str(&sha)?),
+        None => None,
+    };
+    let previous = match env("GOA_PREVIOUS_COMMIT_ID") {
+        Some(previous) => Some(Oid::from_str(&previous)?),
+        None => None,
+    };
+
+    let (commits, files, stats) = match (previous, sha) {
+        (Some(previous), Some(sha)) => {
+            let commits = git::commits_between(local_repo, previous, sha)?;
+            let (files, stats) = git::changed_files(local_repo, previous, sha)?;
+            (commits, files, stats)
+        }
+        (None, Some(sha)) => (
+            vec![local_repo.find_commit(sha)?],
+            Vec::new(),
+            DiffStats::default(),
+        ),
+        _ => (Vec::new(), Vec::new(), DiffStats::default()),
+    };
+
+    Ok(Event {
+        run_id: env("GOA_RUN_ID"),
+        repo_url: public_url(&repo.url),
+        trigger: env("GOA_TRIGGER"),
+        branch: env("GOA_BRANCH"),
+        branch_event: env("GOA_BRANCH_EVENT"),
+        tag: env("GOA_TAG"),
+        directory: env("GOA_DIRECTORY"),
+        previous_sha: env("GOA_PREVIOUS_COMMIT_ID"),
+        sha: env("GOA_LAST_COMMIT_ID"),
+        commits: commits.iter().map(EventCommit::from).collect(),
+        files,
+        stats,
+    })
+}
+
+/// Write the description of the change to `path`
+pub fn write(repo: &Repo, path: &Path) -> Result<()> {
+    let json = serde_json::to_string(&describe(repo)?)?;
+    std::fs::write(path, format!("{}\n", json))
+}
+
+#[cfg(test)]
+mod event_tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let event = Event {
+            run_id: Some(String::from("1")),
+            repo_url: String::from("https://github.com/kitplummer/goa_tester"),
+            trigger: Some(String::from("diff")),
+            branch: Some(String::from("main")),
+            branch_event: None,
+            tag: None,
+            directory: None,
+            previous_sha: None,
+            sha: Some(String::from("4b8e")),
+            commits: vec![EventCommit {
+                id: String::from("4b8e"),
+                author: String::from("Kit Plummer"),
+                email: String::from("kitplummer@gmail.com"),
+                message: String::from("fix \"it\"\n\tfor\\real\u{1}"),
+                time: String::from("2022-01-01T00:00:00+00:00"),
+            }],
+            files: vec![FileChange {
+                path: String::from("README.md"),
+                status: "modified",
+                old_path: None,
+            }],
+            stats: DiffStats {
+                files_changed: 1,
+                insertions: 1,
+                deletions: 0,
+            },
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+        assert_eq!(json["branch_event"], serde_json::Value::Null);
+        assert_eq!(
+            json["commits"][0]["message"],
+            "fix \"it\"\n\tfor\\real\u{1}"
+        );
+        assert_eq!(json["files"][0]["status"], "modified");
+        assert_eq!(json["stats"]["insertions"], 1);
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{Error, ErrorKind, Result};
+
+use glob::{MatchOptions, Pattern};
+
+/// Globs over the paths changed by a fetch, deciding whether they fire the
+/// command. `*` stays within a directory and `**` crosses directories.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PathFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<PathFilter> {
+        Ok(PathFilter {
+            include: compile(include, "path filter")?,
+            exclude: compile(exclude, "path filter")?,
+        })
+    }
+
+    /// A path matches when it matches any include (or there are none) and no
+    /// exclude
+    pub fn matches(&self, path: &str) -> bool {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|p| p.matches_with(path, MATCH_OPTIONS));
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|p| p.matches_with(path, MATCH_OPTIONS))
+    }
+
+    /// The paths that match, in their original order
+    pub fn filter(&self, paths: &[String]) -> Vec<String> {
+        paths
+            .iter()
+            .filter(|path| self.matches(path))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Commits that shouldn't fire the command: those marked `[skip goa]`, those
+/// by ignored authors, and those missing a required trailer
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommitFilter {
+    ignore_authors: Vec<Pattern>,
+    require_trailers: Vec<(String, String)>,
+}
+
+pub const SKIP_DIRECTIVE: &str = "[skip goa]";
+
+impl CommitFilter {
+    /// Authors are globs over the name or email, e.g. `*@bots.example.com`,
+    /// and trailers are `Key: value`, e.g. `Deploy: production`
+    pub fn new(ignore_authors: &[String], require_trailers: &[String]) -> Result<CommitFilter> {
+        let require_trailers = require_trailers
+            .iter()
+            .map(|trailer| match parse_trailer(trailer) {
+                Some(trailer) => Ok(trailer),
+                None => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid trailer {}, expected 'Key: value'", trailer),
+                )),
+            })
+            .collect::<Result<_>>()?;
+        Ok(CommitFilter {
+            ignore_authors: compile(ignore_authors, "author filter")?,
+            require_trailers,
+        })
+    }
+
+    /// Why a commit is ignored, or None when it should fire the command
+    pub fn skip_reason(&self, commit: &git2::Commit) -> Option<String> {
+        let message = commit.message().unwrap_or_default();
+        if message.contains(SKIP_DIRECTIVE) {
+            return Some(format!("its message has {}", SKIP_DIRECTIVE));
+        }
+
+        let author = commit.author();
+        let name = author.name().unwrap_or_default();
+        let email = author.email().unwrap_or_default();
+        // Exact matches too, as bot names like dependabot[bot] aren't globs
+        let ignored = self.ignore_authors.iter().any(|p| {
+            p.as_str() == name || p.as_str() == email || p.matches(name) || p.matches(email)
+        });
+        if ignored {
+            return Some(format!("{} <{}> is an ignored author", name, email));
+        }
+
+        let trailers = trailers(message);
+        for (key, value) in &self.require_trailers {
+            let found = trailers
+                .iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case(key) && v == value);
+            if !found {
+                return Some(format!("it has no {}: {} trailer", key, value));
+            }
+        }
+        None
+    }
+}
+
+fn parse_trailer(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.split_once(':')?;
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((key.to_string(), value.trim().to_string()))
+}
+
+/// The `Key: value` lines of a message's last paragraph
+fn trailers(message: &str) -> Vec<(String, String)> {
+    let paragraphs: Vec<&str> = message
+        .trim()
+        .split("\n\n")
+        .filter(|p| !p.trim().is_empty())
+        .collect();
+    // The subject alone has no trailers
+    if paragraphs.len() < 2 {
+        return vec![];
+    }
+    paragraphs[paragraphs.len() - 1]
+        .lines()
+        .filter_map(parse_trailer)
+        .collect()
+}
+
+fn compile(globs: &[String], kind: &str) -> Result<Vec<Pattern>> {
+    globs
+        .iter()
+        .map(|glob| {
+            Pattern::new(glob).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid {} {}: {}", kind, glob, e),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod filters_tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_empty_matches_everything() {
+        let filter = PathFilter::default();
+        assert!(filter.matches("README.md"));
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = PathFilter::new(
+            &strings(&["services/api/**", "*.toml"]),
+            &strings(&["**/*.md"]),
+        )
+        .unwrap();
+        assert!(filter.matches("services/api/src/main.rs"));
+        assert!(filter.matches("Cargo.toml"));
+        assert!(!filter.matches("services/api/README.md"));
+        assert!(!filter.matches("services/web/index.js"));
+        // `*` doesn't cross directories
+        assert!(!filter.matches("services/Cargo.toml"));
+
+        let paths = strings(&["README.md", "services/api/lib.rs", "docs/a.md"]);
+        assert_eq!(filter.filter(&paths), strings(&["services/api/lib.rs"]));
+    }
+
+    #[test]
+    fn test_exclude_only() {
+        let filter = PathFilter::new(&[], &strings(&["*.md", "docs/**"])).unwrap();
+        assert!(!filter.matches("README.md"));
+        assert!(!filter.matches("docs/guide/intro.txt"));
+        assert!(filter.matches("src/main.rs"));
+    }
+
+    #[test]
+    fn test_trailers() {
+        let message = "Ship it\n\nSome: body: text\n\nDeploy: production\nSigned-off-by: Kit <kit@example.com>\n";
+        assert_eq!(
+            trailers(message),
+            vec![
+                (String::from("Deploy"), String::from("production")),
+                (
+                    String::from("Signed-off-by"),
+                    String::from("Kit <kit@example.com>")
+                ),
+            ]
+        );
+        assert!(trailers("Deploy: production").is_empty());
+        assert!(CommitFilter::new(&[], &strings(&["not a trailer"])).is_err());
+    }
+
+    #[test]
+    fn test_skip_reason() {
+        let (upstream, _) = crate::testing::init_upstream();
+        let commit = |message: &str| {
+            let oid = crate::testing::commit_file(&upstream, "a.txt", message, message);
+            upstream.find_commit(oid).unwrap()
+        };
+
+        let filter = CommitFilter::new(
+            &strings(&["Kit Plummer"]),
+            &strings(&["Deploy: production"]),
+        )
+        .unwrap();
+        assert!(filter
+            .skip_reason(&commit("docs [skip goa]"))
+            .unwrap()
+            .contains("[skip goa]"));
+        assert!(filter
+            .skip_reason(&commit("fix\n\nDeploy: production"))
+            .unwrap()
+            .contains("ignored author"));
+
+        let filter = CommitFilter::new(&[], &strings(&["deploy: production"])).unwrap();
+        assert!(filter.skip_reason(&commit("fix")).is_some());
+        assert!(filter
+            .skip_reason(&commit("fix\n\nDeploy: staging"))
+            .is_some());
+        assert_eq!(
+            filter.skip_reason(&commit("fix\n\nDeploy: production")),
+            None
+        );
+        assert_eq!(CommitFilter::default().skip_reason(&commit("fix")), None);
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        let e = PathFilter::new(&strings(&["src/[a"]), &[]).unwrap_err();
+        assert!(e.to_string().starts_with("invalid path filter src/[a"));
+        let e = CommitFilter::new(&strings(&["bot[a"]), &[]).unwrap_err();
+        assert!(e.to_string().starts_with("invalid author filter bot[a"));
+    }
+}
@@ -12,26 +12,38 @@
  * <http://creativecommons.org/publicdomain/zero/1.0/>.
  */
 
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, FixedOffset, Utc};
 use git2::{
-    AutotagOption, Commit, Diff, DiffStatsFormat, FetchOptions, Object, ObjectType,
-    RemoteCallbacks, Repository,
+    AutotagOption, BranchType, Commit, Cred, Delta, Diff, DiffStatsFormat, Direction, FetchOptions,
+    FetchPrune, Object, ObjectType, Oid, Pathspec, PathspecFlags, RemoteCallbacks, Repository,
+    SubmoduleUpdateOptions,
 };
-use std::env;
+use glob::Pattern;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::str;
+use url::Url;
 
-pub fn is_diff<'a>(
-    repo: &'a git2::Repository,
+/// Environment variables describing a commit, handed to the executed command
+pub type Envs = HashMap<String, String>;
+
+pub fn fetch(
+    repo: &git2::Repository,
     remote_name: &str,
-    branch_name: &str,
+    tags: bool,
+    depth: Option<u32>,
     verbosity: u8,
-) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
+) -> Result<(), git2::Error> {
+    if let Some(depth) = depth {
+        return fetch_shallow(repo, remote_name, tags, depth);
+    }
     let mut cb = RemoteCallbacks::new();
     let mut remote = repo
         .find_remote(remote_name)
-        .or_else(|_| repo.remote_anonymous(remote_name))
-        .unwrap();
+        .or_else(|_| repo.remote_anonymous(remote_name))?;
     cb.sideband_progress(|data| {
         if verbosity > 2 {
             let dt = Utc::now();
@@ -47,35 +59,413 @@ pub fn is_diff<'a>(
 
     let mut fo = FetchOptions::new();
     fo.remote_callbacks(cb);
-    remote.download(&[] as &[&str], Some(&mut fo)).unwrap();
+    // Every tag is needed when spying for new ones, not just those pointing
+    // into the fetched history
+    fo.download_tags(if tags {
+        AutotagOption::All
+    } else {
+        AutotagOption::Unspecified
+    });
+    // Drop remote-tracking branches deleted on the remote, which is how a
+    // deleted branch is noticed
+    fo.prune(FetchPrune::On);
 
-    // Disconnect the underlying connection to prevent from idling.
-    remote.disconnect().unwrap();
+    // Download, disconnect to prevent idling, then update the references in
+    // the remote's namespace to point to the right commits. The tips may need
+    // updating even if there was no packfile to download, which can happen
+    // e.g. when the branches have been changed but all the needed objects are
+    // available locally.
+    remote.fetch(&[] as &[&str], Some(&mut fo), None)?;
 
-    // Update the references in the remote's namespace to point to the right
-    // commits. This may be needed even if there was no packfile to download,
-    // which can happen e.g. when the branches have been changed but all the
-    // needed objects are available locally.
-    remote
-        .update_tips(None, true, AutotagOption::Unspecified, None)
-        .unwrap();
+    Ok(())
+}
 
-    let l = String::from(branch_name);
-    let r = format!("{}/{}", remote_name, branch_name);
-    let tl = tree_to_treeish(repo, Some(&l)).unwrap();
-    let tr = tree_to_treeish(repo, Some(&r)).unwrap();
+/// Fetch only the last `depth` commits of each branch. libgit2 can't fetch
+/// shallow, so the git command line does.
+fn fetch_shallow(
+    repo: &git2::Repository,
+    remote_name: &str,
+    tags: bool,
+    depth: u32,
+) -> Result<(), git2::Error> {
+    let mut command = Command::new("git");
+    command
+        .arg("--git-dir")
+        .arg(repo.path())
+        .args(["fetch", "--quiet", "--prune", "--depth"])
+        .arg(depth.to_string());
+    if tags {
+        command.arg("--tags");
+    }
+    let output = command.arg(remote_name).output().map_err(|e| {
+        git2::Error::from_str(&format!("failed to run git, which --depth needs: {}", e))
+    })?;
+    if !output.status.success() {
+        return Err(git2::Error::from_str(
+            String::from_utf8_lossy(&output.stderr).trim(),
+        ));
+    }
+    Ok(())
+}
 
-    let head = repo.head().unwrap();
-    let oid = head.target().unwrap();
-    let commit = repo.find_commit(oid).unwrap();
+/// Clone only the refs the refspecs name, or every branch when there are
+/// none, keeping the last `depth` commits of history when given, and check
+/// out the sparse paths of the first branch matching `branch`, a name or
+/// glob, or of the first branch fetched when none does
+#[allow(clippy::too_many_arguments)]
+pub fn clone_narrow(
+    url: &str,
+    path: &Path,
+    refspecs: &[String],
+    branch: Option<&str>,
+    sparse: &[String],
+    tags: bool,
+    depth: Option<u32>,
+    verbosity: u8,
+) -> Result<Repository, git2::Error> {
+    let repo = Repository::init(path)?;
+    set_sparse_patterns(&repo, sparse)?;
+    repo.remote("origin", url)?;
+    if !refspecs.is_empty() {
+        repo.config()?
+            .remove_multivar("remote.origin.fetch", ".*")?;
+        for refspec in refspecs {
+            repo.remote_add_fetch("origin", refspec)?;
+        }
+    }
+    fetch(&repo, "origin", tags, depth, verbosity)?;
+
+    let spied = match branch {
+        Some(branch) => repo
+            .references_glob(&format!("refs/remotes/origin/{}", branch))?
+            .flatten()
+            .find_map(|reference| reference.target()),
+        None => None,
+    };
+    let first = match spied {
+        Some(oid) => Some(oid),
+        None => repo
+            .references_glob("refs/remotes/origin/*")?
+            .flatten()
+            .find_map(|reference| reference.target()),
+    };
+    if let Some(oid) = first {
+        repo.set_head_detached(oid)?;
+        repo.checkout_head(Some(checkout_options(&repo).force()))?;
+    }
+    Ok(repo)
+}
 
-    let _branch = repo.branch(branch_name, &commit, false);
+/// The credentials in a remote's url, offered only to the same host
+struct Credentials {
+    host: String,
+    username: String,
+    password: String,
+}
+
+fn credentials_of(url: &str) -> Option<Credentials> {
+    let url = Url::parse(url).ok()?;
+    Some(Credentials {
+        host: url.host_str()?.to_string(),
+        username: url.username().to_string(),
+        password: url.password()?.to_string(),
+    })
+}
+
+/// Initialize and update every submodule, and theirs in turn, to the commit
+/// the checked out tree points it at. Submodules on the same host as the
+/// origin are fetched with the origin's credentials.
+pub fn update_submodules(repo: &Repository) -> Result<(), git2::Error> {
+    let origin = repo.find_remote("origin")?;
+    let credentials = origin.url().and_then(credentials_of);
+    update_submodules_with(repo, credentials.as_ref())
+}
+
+fn update_submodules_with(
+    repo: &Repository,
+    credentials: Option<&Credentials>,
+) -> Result<(), git2::Error> {
+    for mut submodule in repo.submodules()? {
+        let mut callbacks = RemoteCallbacks::new();
+        if let Some(credentials) = credentials {
+            // libgit2 asks again for as long as the credentials are refused
+            let mut offered = false;
+            callbacks.credentials(move |url, _, _| {
+                let host = Url::parse(url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(String::from));
+                if offered || host.as_deref() != Some(credentials.host.as_str()) {
+                    return Err(git2::Error::from_str("no credentials for this remote"));
+                }
+                offered = true;
+                Cred::userpass_plaintext(&credentials.username, &credentials.password)
+            });
+        }
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force();
+        let mut options = SubmoduleUpdateOptions::new();
+        options.fetch(fetch_options).checkout(checkout);
+        submodule.update(true, Some(&mut options))?;
+        update_submodules_with(&submodule.open()?, credentials)?;
+    }
+    Ok(())
+}
+
+/// Limit the working directory to the paths matching the patterns, or lift
+/// the limit when there are none. They're goa's own, not git's sparse
+/// checkout: libgit2 doesn't mark the paths it leaves out as skipped, so the
+/// git command line sees them as deleted. When the patterns change, a checked
+/// out working directory is brought in line with them.
+pub fn set_sparse_patterns(repo: &Repository, patterns: &[String]) -> Result<(), git2::Error> {
+    if sparse_patterns(repo) == patterns {
+        return Ok(());
+    }
+    let sparse_file = sparse_file(repo);
+    if patterns.is_empty() {
+        let _ = std::fs::remove_file(&sparse_file);
+    } else {
+        std::fs::create_dir_all(repo.path().join("goa"))
+            .and_then(|_| std::fs::write(&sparse_file, patterns.join("\n") + "\n"))
+            .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    }
+    if repo.head().is_err() {
+        return Ok(());
+    }
+    repo.checkout_head(Some(checkout_options(repo).force()))?;
+    remove_outside(repo, patterns)
+}
+
+fn sparse_file(repo: &Repository) -> PathBuf {
+    repo.path().join("goa/sparse-checkout")
+}
+
+fn sparse_patterns(repo: &Repository) -> Vec<String> {
+    std::fs::read_to_string(sparse_file(repo))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().trim_matches('/'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Delete the tracked files that don't match the patterns from the working
+/// directory, with the directories they leave empty
+fn remove_outside(repo: &Repository, patterns: &[String]) -> Result<(), git2::Error> {
+    if patterns.is_empty() {
+        return Ok(());
+    }
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return Ok(()),
+    };
+    let pathspec = Pathspec::new(patterns.iter())?;
+    for entry in repo.index()?.iter() {
+        let path = String::from_utf8_lossy(&entry.path).to_string();
+        if pathspec.matches_path(Path::new(&path), PathspecFlags::DEFAULT) {
+            continue;
+        }
+        let file = workdir.join(&path);
+        if std::fs::remove_file(&file).is_err() {
+            continue;
+        }
+        let mut dir = file.parent();
+        while let Some(parent) = dir.filter(|dir| *dir != workdir) {
+            if std::fs::remove_dir(parent).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+    }
+    Ok(())
+}
+
+/// Checkout options that only touch the sparse checkout's paths, when the
+/// repo has any, so nothing else reaches the working directory
+fn checkout_options(repo: &Repository) -> git2::build::CheckoutBuilder<'static> {
+    let mut options = git2::build::CheckoutBuilder::new();
+    for pattern in sparse_patterns(repo) {
+        options.path(pattern);
+    }
+    options
+}
+
+/// The branch names, local or on the remote, that match a branch name or
+/// glob pattern (e.g. `release/*`)
+pub fn matching_branches(
+    repo: &git2::Repository,
+    remote_name: &str,
+    pattern: &str,
+) -> Result<BTreeSet<String>, git2::Error> {
+    let glob = Pattern::new(pattern).map_err(|e| {
+        git2::Error::from_str(&format!("invalid branch pattern {}, {}", pattern, e))
+    })?;
+    let remote_prefix = format!("{}/", remote_name);
+
+    let mut names = BTreeSet::new();
+    for branch in repo.branches(None)? {
+        let (branch, branch_type) = branch?;
+        let name = match branch.name()? {
+            Some(name) => name,
+            None => continue,
+        };
+        let name = match branch_type {
+            BranchType::Local => name,
+            BranchType::Remote => match name.strip_prefix(&remote_prefix) {
+                Some("HEAD") | None => continue,
+                Some(name) => name,
+            },
+        };
+        if glob.matches(name) {
+            names.insert(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// The tag names matching a glob pattern (e.g. `v*`)
+pub fn matching_tags(
+    repo: &git2::Repository,
+    pattern: &str,
+) -> Result<BTreeSet<String>, git2::Error> {
+    let tags = repo.tag_names(Some(pattern))?;
+    Ok(tags.iter().flatten().map(String::from).collect())
+}
+
+/// Check out the commit a tag points to, detaching HEAD from any branch
+pub fn checkout_tag(
+    repo: &git2::Repository,
+    tag_name: &str,
+    verbosity: u8,
+) -> Result<Envs, git2::Error> {
+    let commit = repo
+        .revparse_single(&format!("refs/tags/{}", tag_name))?
+        .peel_to_commit()?;
+    repo.checkout_tree(commit.as_object(), Some(checkout_options(repo).force()))?;
+    repo.set_head_detached(commit.id())?;
+    Ok(commit_to_envs(&commit, verbosity))
+}
+
+/// Check out a commit, with a detached HEAD, returning the environment for
+/// the command
+pub fn checkout_commit(repo: &Repository, oid: Oid, verbosity: u8) -> Result<Envs, git2::Error> {
+    let commit = repo.find_commit(oid)?;
+    repo.checkout_tree(commit.as_object(), Some(checkout_options(repo).force()))?;
+    repo.set_head_detached(oid)?;
+    Ok(commit_to_envs(&commit, verbosity))
+}
+
+/// Move a local branch on to a commit and check it out, as merging the
+/// commits up to it would
+pub fn advance_branch(repo: &Repository, branch_name: &str, oid: Oid) -> Result<(), git2::Error> {
+    let refname = format!("refs/heads/{}", branch_name);
+    let mut branch = repo.find_reference(&refname)?;
+    branch.set_target(oid, &format!("Advance: Setting {} to id: {}", refname, oid))?;
+    repo.set_head(&refname)?;
+    repo.checkout_head(Some(checkout_options(repo).force()))?;
+    Ok(())
+}
+
+pub fn has_branch(repo: &git2::Repository, branch_name: &str, branch_type: BranchType) -> bool {
+    repo.find_branch(branch_name, branch_type).is_ok()
+}
+
+/// Remove a local branch whose remote branch was deleted
+pub fn delete_branch(repo: &git2::Repository, branch_name: &str) -> Result<(), git2::Error> {
+    let mut branch = repo.find_branch(branch_name, BranchType::Local)?;
+    if branch.is_head() {
+        let commit = branch.get().peel_to_commit()?;
+        repo.set_head_detached(commit.id())?;
+    }
+    branch.delete()
+}
+
+/// The commit a branch points to on the remote, asking the remote without
+/// fetching. None when the remote has no such branch.
+pub fn remote_tip(
+    repo: &git2::Repository,
+    remote_name: &str,
+    branch_name: &str,
+) -> Result<Option<Oid>, git2::Error> {
+    let mut remote = repo
+        .find_remote(remote_name)
+        .or_else(|_| repo.remote_anonymous(remote_name))?;
+    remote.connect(Direction::Fetch)?;
+    let refname = format!("refs/heads/{}", branch_name);
+    let tip = remote
+        .list()?
+        .iter()
+        .find(|head| head.name() == refname)
+        .map(|head| head.oid());
+    remote.disconnect()?;
+    Ok(tip)
+}
+
+/// Whether the fetched remote-tracking branch points somewhere other than
+/// the local branch
+pub fn branch_moved(repo: &git2::Repository, remote_name: &str, branch_name: &str) -> bool {
+    let local = repo.refname_to_id(&format!("refs/heads/{}", branch_name));
+    let remote = repo.refname_to_id(&format!("refs/remotes/{}/{}", remote_name, branch_name));
+    matches!((local, remote), (Ok(local), Ok(remote)) if local != remote)
+}
+
+/// Create the local branch from its remote-tracking branch, unless it exists
+pub fn track_branch(
+    repo: &git2::Repository,
+    remote_name: &str,
+    branch_name: &str,
+) -> Result<(), git2::Error> {
+    if repo.find_branch(branch_name, BranchType::Local).is_ok() {
+        return Ok(());
+    }
+    let remote_branch = format!("{}/{}", remote_name, branch_name);
+    let commit = match repo.find_branch(&remote_branch, BranchType::Remote) {
+        Ok(remote) => remote.get().peel_to_commit()?,
+        Err(_) => {
+            return Err(git2::Error::from_str(&format!(
+                "branch {} not found on {}",
+                branch_name, remote_name
+            )))
+        }
+    };
+    let mut branch = repo.branch(branch_name, &commit, false)?;
+    branch.set_upstream(Some(&remote_branch))?;
+    Ok(())
+}
+
+/// A fetched commit that differs from the local branch
+pub struct Change<'a> {
+    /// The local branch's tip before the merge
+    pub old: Oid,
+    pub commit: git2::AnnotatedCommit<'a>,
+    /// Every path added, modified, deleted or renamed (old and new path)
+    pub paths: Vec<String>,
+}
+
+/// Check out `branch_name` and compare it with its remote-tracking branch,
+/// which should already have been fetched. None when there is nothing new.
+pub fn is_diff<'a>(
+    repo: &'a git2::Repository,
+    remote_name: &str,
+    branch_name: &str,
+    verbosity: u8,
+) -> Result<Option<Change<'a>>, git2::Error> {
+    track_branch(repo, remote_name, branch_name)?;
+
+    let l = String::from(branch_name);
+    let r = format!("{}/{}", remote_name, branch_name);
+    let tl = tree_to_treeish(repo, Some(&l))?;
+    let tr = tree_to_treeish(repo, Some(&r))?;
 
     let obj = repo
         .revparse_single(&("refs/heads/".to_owned() + branch_name))
         .unwrap();
 
-    repo.checkout_tree(&obj, None)?;
+    // Several branches may share this clone, so switching between them has to
+    // win over whatever the previous branch left in the working directory
+    repo.checkout_tree(&obj, Some(checkout_options(repo).force()))?;
 
     repo.set_head(&("refs/heads/".to_owned() + branch_name))?;
 
@@ -91,17 +481,21 @@ pub fn is_diff<'a>(
         if verbosity > 2 {
             display_stats(&diff).expect("ERROR: unable to print diff stats");
         }
-        let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        repo.reference_to_annotated_commit(&fetch_head)
+        let remote_ref = repo.find_reference(&format!("refs/remotes/{}", r))?;
+        let commit = repo.reference_to_annotated_commit(&remote_ref)?;
+        Ok(Some(Change {
+            old: obj.id(),
+            commit,
+            paths: delta_paths(&diff),
+        }))
     } else {
-        let msg = "no diffs, back to sleep.";
-        Err(git2::Error::from_str(msg))
+        Ok(None)
     }
 }
 
-pub fn set_last_commit(repo: &git2::Repository, branch_name: &str, verbosity: u8) {
+pub fn set_last_commit(repo: &git2::Repository, branch_name: &str, verbosity: u8) -> Envs {
     let commit = find_last_commit_on_branch(repo, branch_name);
-    commit_to_envs(&commit.unwrap(), verbosity);
+    commit_to_envs(&commit.unwrap(), verbosity)
 }
 
 pub fn tree_to_treeish<'a>(
@@ -115,20 +509,162 @@ pub fn tree_to_treeish<'a>(
     let obj = match repo.revparse_single(arg) {
         Ok(obj) => obj,
         Err(_) => {
-            eprintln!("Error: branch not found");
-            std::process::exit(1);
+            return Err(git2::Error::from_str(&format!("branch {} not found", arg)));
         }
     };
     let tree = obj.peel(ObjectType::Tree).unwrap();
     Ok(Some(tree))
 }
 
+/// The commits reachable from `new` but not `old`, oldest first
+pub fn commits_between(
+    repo: &Repository,
+    old: Oid,
+    new: Oid,
+) -> Result<Vec<Commit<'_>>, git2::Error> {
+    let walked = repo.revwalk().and_then(|mut revwalk| {
+        revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+        revwalk.push(new)?;
+        revwalk.hide(old)?;
+        revwalk
+            .map(|oid| oid.and_then(|oid| repo.find_commit(oid)))
+            .collect()
+    });
+    match walked {
+        // libgit2 can't walk past the missing parents of a shallow clone
+        Err(_) if repo.is_shallow() => shallow_commits_between(repo, old, new),
+        walked => walked,
+    }
+}
+
+/// The commits reachable from `new` but not `old` in a shallow clone, as far
+/// back as its history goes, oldest first
+fn shallow_commits_between(
+    repo: &Repository,
+    old: Oid,
+    new: Oid,
+) -> Result<Vec<Commit<'_>>, git2::Error> {
+    let ancestors = |from: Oid| {
+        let mut seen = Vec::new();
+        let mut queue = vec![from];
+        while let Some(oid) = queue.pop() {
+            if seen.iter().any(|c: &Commit| c.id() == oid) {
+                continue;
+            }
+            // A missing commit is where the shallow history stops
+            if let Ok(commit) = repo.find_commit(oid) {
+                queue.extend(commit.parent_ids());
+                seen.push(commit);
+            }
+        }
+        seen
+    };
+    let hidden: Vec<Oid> = ancestors(old).iter().map(Commit::id).collect();
+    let mut commits: Vec<Commit> = ancestors(new)
+        .into_iter()
+        .filter(|commit| !hidden.contains(&commit.id()))
+        .collect();
+    commits.sort_by_key(|commit| commit.time().seconds());
+    Ok(commits)
+}
+
+/// A file that differs between two commits
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    /// added, deleted, modified, renamed, copied or typechange
+    pub status: &'static str,
+    /// Where a renamed or copied file came from
+    pub old_path: Option<String>,
+}
+
+/// Lines and files changed between two commits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct DiffStats {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+/// The files that differ between two commits, with renames found, and how
+/// much they changed
+pub fn changed_files(
+    repo: &Repository,
+    old: Oid,
+    new: Oid,
+) -> Result<(Vec<FileChange>, DiffStats), git2::Error> {
+    let old_tree = repo.find_commit(old)?.tree()?;
+    let new_tree = repo.find_commit(new)?.tree()?;
+    let mut diff = repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)?;
+    diff.find_similar(None)?;
+
+    let path = |file: git2::DiffFile| file.path().map(|p| p.to_string_lossy().to_string());
+    let files = diff
+        .deltas()
+        .map(|delta| {
+            let status = match delta.status() {
+                Delta::Added => "added",
+                Delta::Deleted => "deleted",
+                Delta::Renamed => "renamed",
+                Delta::Copied => "copied",
+                Delta::Typechange => "typechange",
+                _ => "modified",
+            };
+            let old_path = match delta.status() {
+                Delta::Renamed | Delta::Copied => path(delta.old_file()),
+                _ => None,
+            };
+            FileChange {
+                path: path(delta.new_file())
+                    .or_else(|| path(delta.old_file()))
+                    .unwrap_or_default(),
+                status,
+                old_path,
+            }
+        })
+        .collect();
+    let stats = diff.stats()?;
+    Ok((
+        files,
+        DiffStats {
+            files_changed: stats.files_changed(),
+            insertions: stats.insertions(),
+            deletions: stats.deletions(),
+        },
+    ))
+}
+
+/// The paths a commit changes relative to its first parent
+pub fn commit_paths(repo: &Repository, commit: &Commit) -> Result<Vec<String>, git2::Error> {
+    // The oldest commit of a shallow clone has its parent missing, and is
+    // taken to change every path
+    let parent = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) if commit.parent_count() == 0 || repo.is_shallow() => None,
+        Err(e) => return Err(e),
+    };
+    let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+    Ok(delta_paths(&diff))
+}
+
+fn delta_paths(diff: &Diff) -> Vec<String> {
+    let mut paths = BTreeSet::new();
+    for delta in diff.deltas() {
+        for file in [delta.old_file(), delta.new_file()] {
+            if let Some(path) = file.path() {
+                paths.insert(path.to_string_lossy().to_string());
+            }
+        }
+    }
+    paths.into_iter().collect()
+}
+
 fn display_stats(diff: &Diff) -> Result<(), git2::Error> {
     let stats = diff.stats().unwrap();
     let format = DiffStatsFormat::FULL;
     let buf = stats.to_buf(format, 80).unwrap();
     let dt = Utc::now();
-    print!("goa [{}]: {}", dt, std::str::from_utf8(&*buf).unwrap());
+    print!("goa [{}]: {}", dt, std::str::from_utf8(&buf).unwrap());
     Ok(())
 }
 
@@ -138,7 +674,7 @@ fn find_last_commit_on_branch<'a>(
 ) -> Result<Commit<'a>, git2::Error> {
     let (object, reference) = repo.revparse_ext(branch_name).expect("Object not found");
 
-    repo.checkout_tree(&object, None)
+    repo.checkout_tree(&object, Some(checkout_options(repo).force()))
         .expect("Failed to checkout");
 
     match reference {
@@ -154,15 +690,23 @@ fn find_last_commit_on_branch<'a>(
         .map_err(|_| git2::Error::from_str("Couldn't find commit"))
 }
 
-fn find_last_commit(repo: &Repository) -> Result<Commit, git2::Error> {
+fn find_last_commit(repo: &Repository) -> Result<Commit<'_>, git2::Error> {
     let obj = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
     obj.into_commit()
         .map_err(|_| git2::Error::from_str("Couldn't find commit"))
 }
 
-fn commit_to_envs(commit: &Commit, verbosity: u8) {
-    let timestamp = commit.time().seconds();
-    let tm = NaiveDateTime::from_timestamp(timestamp, 0);
+/// A commit or signature time as ISO-8601, in the offset it was made in
+pub fn iso_time(time: git2::Time) -> String {
+    FixedOffset::east_opt(time.offset_minutes() * 60)
+        .and_then(|offset| {
+            DateTime::from_timestamp(time.seconds(), 0).map(|utc| utc.with_timezone(&offset))
+        })
+        .map_or_else(String::new, |time| time.to_rfc3339())
+}
+
+fn commit_to_envs(commit: &Commit, verbosity: u8) -> Envs {
+    let time = iso_time(commit.time());
     if verbosity > 0 {
         let dt = Utc::now();
         println!(
@@ -170,14 +714,45 @@ fn commit_to_envs(commit: &Commit, verbosity: u8) {
             dt,
             commit.id(),
             commit.author(),
-            tm,
+            time,
             commit.message().unwrap_or("no commit message")
         );
     }
-    env::set_var("GOA_LAST_COMMIT_ID", commit.id().to_string());
-    env::set_var("GOA_LAST_COMMIT_AUTHOR", commit.author().to_string());
-    env::set_var("GOA_LAST_COMMIT_MESSAGE", commit.author().to_string());
-    env::set_var("GOA_LAST_COMMIT_TIME", tm.to_string());
+    let author = commit.author();
+    let committer = commit.committer();
+    let mut envs = Envs::new();
+    envs.insert(String::from("GOA_LAST_COMMIT_ID"), commit.id().to_string());
+    envs.insert(String::from("GOA_LAST_COMMIT_AUTHOR"), author.to_string());
+    envs.insert(
+        String::from("GOA_LAST_COMMIT_AUTHOR_NAME"),
+        author.name().unwrap_or_default().to_string(),
+    );
+    envs.insert(
+        String::from("GOA_LAST_COMMIT_AUTHOR_EMAIL"),
+        author.email().unwrap_or_default().to_string(),
+    );
+    envs.insert(
+        String::from("GOA_LAST_COMMIT_AUTHOR_TIME"),
+        iso_time(author.when()),
+    );
+    envs.insert(
+        String::from("GOA_LAST_COMMIT_COMMITTER"),
+        committer.to_string(),
+    );
+    envs.insert(
+        String::from("GOA_LAST_COMMIT_COMMITTER_NAME"),
+        committer.name().unwrap_or_default().to_string(),
+    );
+    envs.insert(
+        String::from("GOA_LAST_COMMIT_COMMITTER_EMAIL"),
+        committer.email().unwrap_or_default().to_string(),
+    );
+    envs.insert(
+        String::from("GOA_LAST_COMMIT_MESSAGE"),
+        commit.message().unwrap_or_default().to_string(),
+    );
+    envs.insert(String::from("GOA_LAST_COMMIT_TIME"), time);
+    envs
 }
 
 fn fast_forward(
@@ -193,7 +768,7 @@ fn fast_forward(
     lb.set_target(rc.id(), &msg)?;
     repo.set_head(&name)?;
     repo.checkout_head(Some(
-        git2::build::CheckoutBuilder::default()
+        checkout_options(repo)
             // For some reason the force is required to make the working directory actually get updated
             // I suspect we should be adding some logic to handle dirty working directory states
             // but this is just an example so maybe not.
@@ -216,7 +791,7 @@ fn normal_merge(
 
     if idx.has_conflicts() {
         eprintln!("Error: Merge conficts detected...");
-        repo.checkout_index(Some(&mut idx), None)?;
+        repo.checkout_index(Some(&mut idx), Some(&mut checkout_options(repo)))?;
         return Ok(());
     }
     let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
@@ -235,7 +810,7 @@ fn normal_merge(
         &[&local_commit, &remote_commit],
     )?;
     // Set working tree to match head.
-    repo.checkout_head(None)?;
+    repo.checkout_head(Some(&mut checkout_options(repo)))?;
     Ok(())
 }
 
@@ -244,12 +819,18 @@ pub fn do_merge<'a>(
     remote_branch: &str,
     fetch_commit: git2::AnnotatedCommit<'a>,
     verbosity: u8,
-) -> Result<(), git2::Error> {
+) -> Result<Envs, git2::Error> {
     // 1. do a merge analysis
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
+    let analysis = match repo.merge_analysis(&[&fetch_commit]) {
+        Ok((analysis, _)) => analysis,
+        // A shallow clone may not have the history to find where the branch
+        // and the fetched commit meet, so the branch takes on the commit
+        Err(_) if repo.is_shallow() => git2::MergeAnalysis::ANALYSIS_FASTFORWARD,
+        Err(e) => return Err(e),
+    };
 
     // 2. Do the appopriate merge
-    if analysis.0.is_fast_forward() {
+    if analysis.is_fast_forward() {
         // do a fast forward
         let refname = format!("refs/heads/{}", remote_branch);
         match repo.find_reference(&refname) {
@@ -268,7 +849,7 @@ pub fn do_merge<'a>(
                 )?;
                 repo.set_head(&refname)?;
                 repo.checkout_head(Some(
-                    git2::build::CheckoutBuilder::default()
+                    checkout_options(repo)
                         .allow_conflicts(true)
                         .conflict_style_merge(true)
                         .force(),
@@ -276,15 +857,15 @@ pub fn do_merge<'a>(
             }
         };
         let commit = find_last_commit(repo).expect("Couldn't find last commit");
-        commit_to_envs(&commit, verbosity);
-    } else if analysis.0.is_normal() {
+        Ok(commit_to_envs(&commit, verbosity))
+    } else if analysis.is_normal() {
         // do a normal merge
         let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
         normal_merge(repo, &head_commit, &fetch_commit)?;
         let commit = find_last_commit(repo).expect("Couldn't find last commit");
-        commit_to_envs(&commit, verbosity);
+        Ok(commit_to_envs(&commit, verbosity))
     } else {
         eprintln!("Error: Nothing to do?");
+        Ok(Envs::new())
     }
-    Ok(())
 }
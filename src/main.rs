@@ -1,9 +1,25 @@
 mod cli;
+mod config;
+mod event;
+mod filters;
 mod git;
+mod monorepo;
+mod pipeline;
+mod process;
 mod repos;
+mod retry;
 mod spy;
+mod state;
+mod tags;
+#[cfg(test)]
+mod testing;
+mod verify;
 
-use crate::repos::Repo;
+use crate::filters::{CommitFilter, PathFilter};
+use crate::repos::{Branch, Hooks, Repo};
+use crate::retry::Retry;
+use crate::tags::TagWatch;
+use crate::verify::TrustPolicy;
 use cli::{Action::*, CommandLineArgs};
 use structopt::StructOpt;
 
@@ -18,45 +34,152 @@ fn main() -> anyhow::Result<()> {
     match action {
         Spy {
             url,
-            branch,
+            mut branch,
+            tag,
+            semver,
+            include,
+            exclude,
+            ignore_author,
+            require_trailer,
+            verify_signatures,
+            gpg_keyring,
+            allowed_signers,
+            monorepo,
+            per_commit,
+            depth,
+            single_branch,
+            sparse,
+            submodules,
             delay,
             username,
             token,
             command,
+            on_success,
+            on_failure,
+            always,
+            timeout,
+            failure_policy,
+            retries,
+            retry_delay,
+            retry_backoff,
+            retry_jitter,
+            queue_policy,
             verbosity,
             exec_on_start,
             exit_on_first_diff,
             target_path,
+            state_dir,
         } => {
-            let repo = Repo::new(
+            let mut repo = Repo::new(
                 url,
                 username,
                 token,
                 Some(String::from("initialize")),
                 target_path,
-                branch,
+                String::from("main"),
                 command,
                 delay,
                 verbosity,
                 exec_on_start,
                 exit_on_first_diff,
             );
-
-            let log_level = match verbosity {
-                0 => "error",
-                1 => "info",
-                _ => "debug",
+            // Without a --branch spy main, unless only spying tags
+            if branch.is_empty() && tag.is_none() {
+                branch.push(String::from("main"));
+            }
+            repo.branches = branch
+                .into_iter()
+                .map(|name| Branch::new(name, String::new()))
+                .collect();
+            repo.tag = tag.map(|pattern| TagWatch::new(pattern, semver, String::new()));
+            if monorepo && !repo.command.is_empty() {
+                eprintln!(
+                    "goa error: --monorepo runs the .goa files, it can't be used with --command"
+                );
+                std::process::exit(1);
+            }
+            if depth == Some(0) {
+                eprintln!("goa error: --depth must be 1 or more");
+                std::process::exit(1);
+            }
+            repo.monorepo = monorepo;
+            repo.per_commit = per_commit;
+            repo.depth = depth;
+            repo.single_branch = single_branch;
+            repo.sparse = sparse;
+            repo.submodules = submodules;
+            repo.state_dir = state_dir.or_else(state::default_dir);
+            repo.name = spy::watcher_name(&repo);
+            repo.hooks = Hooks {
+                on_success,
+                on_failure,
+                always,
+            };
+            repo.timeout = timeout;
+            repo.failure_policy = failure_policy;
+            repo.queue_policy = queue_policy;
+            let filters = PathFilter::new(&include, &exclude).and_then(|paths| {
+                Ok((
+                    paths,
+                    CommitFilter::new(&ignore_author, &require_trailer)?,
+                    TrustPolicy::new(verify_signatures, gpg_keyring, allowed_signers)?,
+                    Retry::new(retries, retry_delay, retry_backoff, retry_jitter)?,
+                ))
+            });
+            (repo.paths, repo.commits, repo.trust, repo.retry) = match filters {
+                Ok(filters) => filters,
+                Err(e) => {
+                    eprintln!("goa error: {}", e);
+                    std::process::exit(1);
+                }
             };
 
-            Builder::from_env(Env::default().default_filter_or(log_level))
-                .target(Target::Stdout)
-                .init();
+            init_logger(verbosity);
 
             info!("starting");
 
             spy::spy_repo(repo)
         }
+        Run {
+            config,
+            workers,
+            verbosity,
+        } => {
+            init_logger(verbosity);
+
+            let (repos, workers) = match config::load(&config).and_then(|c| {
+                let workers = workers.or(c.workers).unwrap_or(config::DEFAULT_WORKERS);
+                Ok((c.into_repos(verbosity)?, workers))
+            }) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    eprintln!("goa error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            info!(
+                "starting {} spies from {} on {} workers",
+                repos.len(),
+                config.display(),
+                workers
+            );
+
+            spy::run_config(config, repos, workers, verbosity)
+        }
     }?;
 
     Ok(())
 }
+
+fn init_logger(verbosity: u8) {
+    let log_level = match verbosity {
+        0 => "error",
+        1 => "info",
+        _ => "debug",
+    };
+
+    Builder::from_env(Env::default().default_filter_or(log_level))
+        .target(Target::Stdout)
+        .init();
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::pipeline;
+
+/// The directories of a checkout, relative to its root, holding a `.goa`
+/// or pipeline file. The root itself is `""`.
+pub fn goa_dirs(workdir: &Path) -> Vec<String> {
+    let mut dirs = Vec::new();
+    find_goa_dirs(workdir, "", &mut dirs);
+    dirs.sort();
+    dirs
+}
+
+fn find_goa_dirs(workdir: &Path, dir: &str, dirs: &mut Vec<String>) {
+    let path = workdir.join(dir);
+    if pipeline::has_goa(&path) {
+        dirs.push(dir.to_string());
+    }
+
+    let entries = match std::fs::read_dir(&path) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Symlinks aren't followed, so a link can't send us round in circles
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        if !is_dir || name == ".git" {
+            continue;
+        }
+        let child = if dir.is_empty() {
+            name
+        } else {
+            format!("{}/{}", dir, name)
+        };
+        find_goa_dirs(workdir, &child, dirs);
+    }
+}
+
+/// The directory owning a path: the deepest of `dirs` containing it
+pub fn owner<'a>(dirs: &'a [String], path: &str) -> Option<&'a str> {
+    dirs.iter()
+        .filter(|dir| dir.is_empty() || path.starts_with(&format!("{}/", dir)))
+        .max_by_key(|dir| dir.len())
+        .map(|dir| dir.as_str())
+}
+
+/// The changed paths grouped by the directory owning them, leaving out paths
+/// no directory owns
+pub fn changed_dirs(dirs: &[String], paths: &[String]) -> BTreeMap<String, Vec<String>> {
+    let mut changed: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for path in paths {
+        if let Some(dir) = owner(dirs, path) {
+            changed
+                .entry(dir.to_string())
+                .or_default()
+                .push(path.clone());
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod monorepo_tests {
+    use super::*;
+    use crate::testing;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_goa_dirs() {
+        let root = testing::temp_path();
+        for dir in ["services/api", "services/web", "docs", ".git/hooks"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("services/api/.goa"), "make deploy").unwrap();
+        std::fs::write(root.join("services/web/.goa"), "make deploy").unwrap();
+        std::fs::write(root.join(".git/hooks/.goa"), "nope").unwrap();
+        assert_eq!(goa_dirs(&root), strings(&["services/api", "services/web"]));
+
+        std::fs::write(root.join(".goa"), "make all").unwrap();
+        assert_eq!(
+            goa_dirs(&root),
+            strings(&["", "services/api", "services/web"])
+        );
+    }
+
+    #[test]
+    fn test_changed_dirs() {
+        let dirs = strings(&["services/api", "services/api/worker", "services/web"]);
+        let paths = strings(&[
+            "services/api/src/main.rs",
+            "services/api/worker/job.rs",
+            "services/api-gateway/main.rs",
+            "README.md",
+        ]);
+        let changed = changed_dirs(&dirs, &paths);
+        assert_eq!(changed.len(), 2);
+        assert_eq!(
+            changed["services/api"],
+            strings(&["services/api/src/main.rs"])
+        );
+        assert_eq!(
+            changed["services/api/worker"],
+            strings(&["services/api/worker/job.rs"])
+        );
+
+        // A root .goa picks up everything else
+        let dirs = strings(&["", "services/api"]);
+        let changed = changed_dirs(&dirs, &paths);
+        assert_eq!(
+            changed[""],
+            strings(&["services/api-gateway/main.rs", "README.md"])
+        );
+    }
+}
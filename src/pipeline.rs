@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// What a directory asks to run: the single command of its `.goa` file, in
+/// the original format, or the pipeline of steps of its pipeline file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Goa {
+    Command(String),
+    Pipeline(Pipeline),
+}
+
+/// Named steps, in an order that runs every step after those it depends on
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub name: String,
+    pub command: String,
+    /// Relative to the directory of the pipeline file
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Steps that must succeed before this one runs
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Seconds the step may run before it's killed, defaults to the repo's
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Toml,
+    Yaml,
+}
+
+/// The pipeline files a directory may hold, in the order they're looked for.
+/// A pipeline is opted into by its file name, so a `.goa` file is always a
+/// script, whatever it holds.
+const PIPELINE_FILES: [(&str, Format); 3] = [
+    (".goa.toml", Format::Toml),
+    (".goa.yml", Format::Yaml),
+    (".goa.yaml", Format::Yaml),
+];
+
+/// Whether a directory holds a `.goa` file or a pipeline file
+pub fn has_goa(dir: &Path) -> bool {
+    dir.join(".goa").is_file()
+        || PIPELINE_FILES
+            .iter()
+            .any(|(name, _)| dir.join(name).is_file())
+}
+
+/// Read what a directory asks to run: its pipeline file when it has one,
+/// else its `.goa` file as a single command
+pub fn read(dir: &Path) -> Result<Goa> {
+    for (name, format) in PIPELINE_FILES {
+        let path = dir.join(name);
+        if path.is_file() {
+            return std::fs::read_to_string(&path)
+                .and_then(|contents| parse(&contents, format))
+                .map_err(|e| Error::new(e.kind(), format!("{}: {}", name, e)));
+        }
+    }
+    Ok(Goa::Command(crate::repos::read_goa_file(
+        dir.join(".goa").display().to_string(),
+    )))
+}
+
+fn parse(contents: &str, format: Format) -> Result<Goa> {
+    let pipeline: Pipeline = match format {
+        Format::Toml => toml::from_str(contents).map_err(|e| invalid(e.to_string()))?,
+        Format::Yaml => serde_yaml::from_str(contents).map_err(|e| invalid(e.to_string()))?,
+    };
+    Ok(Goa::Pipeline(pipeline.ordered()?))
+}
+
+impl Pipeline {
+    /// The steps sorted so each comes after its dependencies, keeping the
+    /// order of the file otherwise
+    fn ordered(self) -> Result<Pipeline> {
+        let mut index = HashMap::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            if index.insert(step.name.as_str(), i).is_some() {
+                return Err(invalid(format!(
+                    "step {} is defined more than once",
+                    step.name
+                )));
+            }
+        }
+        for step in &self.steps {
+            for dependency in &step.depends_on {
+                if !index.contains_key(dependency.as_str()) {
+                    return Err(invalid(format!(
+                        "step {} depends on {}, which isn't defined",
+                        step.name, dependency
+                    )));
+                }
+            }
+        }
+
+        let mut order = Vec::with_capacity(self.steps.len());
+        let mut placed = vec![false; self.steps.len()];
+        while order.len() < self.steps.len() {
+            let next = self.steps.iter().enumerate().position(|(i, step)| {
+                !placed[i] && step.depends_on.iter().all(|d| placed[index[d.as_str()]])
+            });
+            match next {
+                Some(i) => {
+                    placed[i] = true;
+                    order.push(i);
+                }
+                None => {
+                    let stuck: Vec<&str> = self
+                        .steps
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| !placed[*i])
+                        .map(|(_, step)| step.name.as_str())
+                        .collect();
+                    return Err(invalid(format!(
+                        "steps {} depend on each other",
+                        stuck.join(", ")
+                    )));
+                }
+            }
+        }
+
+        let mut steps: Vec<Option<Step>> = self.steps.into_iter().map(Some).collect();
+        Ok(Pipeline {
+            steps: order.into_iter().filter_map(|i| steps[i].take()).collect(),
+        })
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid pipeline, {}", msg))
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use super::*;
+
+    fn names(goa: &Goa) -> Vec<&str> {
+        match goa {
+            Goa::Pipeline(pipeline) => pipeline.steps.iter().map(|s| s.name.as_str()).collect(),
+            Goa::Command(_) => panic!("not a pipeline"),
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = crate::testing::temp_path();
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(!has_goa(&dir));
+
+        // A script that happens to mention steps is still a script
+        let script = "cat <<EOF\nsteps:\n[[steps]]\nEOF\n";
+        std::fs::write(dir.join(".goa"), script).unwrap();
+        assert!(has_goa(&dir));
+        assert_eq!(read(&dir).unwrap(), Goa::Command(String::from(script)));
+
+        std::fs::write(
+            dir.join(".goa.yml"),
+            "steps:\n  - name: build\n    command: make\n",
+        )
+        .unwrap();
+        assert_eq!(names(&read(&dir).unwrap()), vec!["build"]);
+
+        std::fs::write(dir.join(".goa.toml"), "[[steps]]\nname = \"a\"\n").unwrap();
+        let e = read(&dir).unwrap_err();
+        assert!(
+            e.to_string().starts_with(".goa.toml: invalid pipeline"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let goa = parse(
+            r#"
+            [[steps]]
+            name = "deploy"
+            command = "./deploy.sh"
+            depends_on = ["build", "test"]
+
+            [[steps]]
+            name = "test"
+            command = "cargo test"
+            depends_on = ["build"]
+
+            [[steps]]
+            name = "build"
+            command = "cargo build"
+            working_dir = "app"
+            env = { RUST_LOG = "info" }
+            "#,
+            Format::Toml,
+        )
+        .unwrap();
+        assert_eq!(names(&goa), vec!["build", "test", "deploy"]);
+
+        if let Goa::Pipeline(pipeline) = goa {
+            assert_eq!(pipeline.steps[0].working_dir, Some(String::from("app")));
+            assert_eq!(pipeline.steps[0].env["RUST_LOG"], "info");
+        }
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let goa = parse(
+            "steps:\n  - name: lint\n    command: make lint\n  - name: build\n    command: make\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        assert_eq!(names(&goa), vec!["lint", "build"]);
+    }
+
+    #[test]
+    fn test_invalid_pipelines() {
+        let cycle = r#"
+            [[steps]]
+            name = "a"
+            command = "true"
+            depends_on = ["b"]
+
+            [[steps]]
+            name = "b"
+            command = "true"
+            depends_on = ["a"]
+            "#;
+        assert!(parse(cycle, Format::Toml)
+            .unwrap_err()
+            .to_string()
+            .contains("depend on each other"));
+
+        let missing = "[[steps]]\nname = \"a\"\ncommand = \"true\"\ndepends_on = [\"b\"]\n";
+        assert!(parse(missing, Format::Toml).is_err());
+
+        let typo = "[[steps]]\nname = \"a\"\ncomand = \"true\"\n";
+        assert!(parse(typo, Format::Toml).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::io::{BufRead, BufReader, Read, Result};
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::git::Envs;
+
+/// What became of a command
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exit {
+    /// It exited with this code, having printed this stdout and stderr
+    Code(i32, String, String),
+    /// It ran past its timeout, and was killed along with everything it
+    /// started, having printed this stdout and stderr until then
+    TimedOut(String, String),
+    /// It was killed, along with everything it started, when asked to stop,
+    /// having printed this stdout and stderr until then
+    Cancelled(String, String),
+}
+
+/// Which of a command's pipes a line came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Called with each line of a command's output as it's printed
+pub type OnLine = Arc<dyn Fn(Stream, &str) + Send + Sync>;
+
+/// Run a shell command from `working_dir`, handing each line of its output
+/// to `on_line`, and killing it and its children if it runs for longer than
+/// `timeout` or once `cancel` says so
+pub fn run(
+    command: &str,
+    envs: &Envs,
+    working_dir: &Path,
+    timeout: Option<Duration>,
+    on_line: OnLine,
+    cancel: &mut dyn FnMut() -> bool,
+) -> Result<Exit> {
+    let mut child = spawn(command, envs, working_dir)?;
+    let stdout = Reader::new(child.stdout.take(), Stream::Stdout, Arc::clone(&on_line));
+    let stderr = Reader::new(child.stderr.take(), Stream::Stderr, on_line);
+
+    // The command is done once the shell has exited and its pipes are
+    // closed, which a child it left in the background may put off for good,
+    // so both wait on the deadline. The pipes may still be held open by a
+    // child that left the group, so their readers are left behind when the
+    // command is killed
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut exited = None;
+    let status = loop {
+        if exited.is_none() {
+            exited = child.try_wait()?;
+        }
+        if let Some(status) = exited {
+            if stdout.is_finished() && stderr.is_finished() {
+                break status;
+            }
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill(&mut child);
+            return Ok(Exit::TimedOut(stdout.output(), stderr.output()));
+        }
+        if cancel() {
+            kill(&mut child);
+            return Ok(Exit::Cancelled(stdout.output(), stderr.output()));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    Ok(Exit::Code(
+        status.code().unwrap_or(-1),
+        stdout.output(),
+        stderr.output(),
+    ))
+}
+
+/// Start the command in a process group of its own, so it can be killed
+/// along with whatever it starts
+#[cfg(unix)]
+fn spawn(command: &str, envs: &Envs, working_dir: &Path) -> Result<Child> {
+    use std::os::unix::process::CommandExt;
+
+    std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(working_dir)
+        .envs(envs)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+}
+
+#[cfg(not(unix))]
+fn spawn(command: &str, envs: &Envs, working_dir: &Path) -> Result<Child> {
+    let mut options = run_script::ScriptOptions::new();
+    options.working_directory = Some(working_dir.to_path_buf());
+    options.env_vars = Some(envs.clone());
+    run_script::spawn(command, &vec![], &options).map_err(|e| std::io::Error::other(e.to_string()))
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // The command leads its group, so the group's id is its pid
+    // SAFETY: killpg only sends a signal, it touches no memory of ours
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+    }
+    let _ = child.wait();
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// The output of a child's pipe, collected on its own thread so a chatty
+/// command can't fill the pipe and stall, passing on each line as it comes
+struct Reader {
+    output: Arc<Mutex<String>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Reader {
+    fn new<R: Read + Send + 'static>(pipe: Option<R>, stream: Stream, on_line: OnLine) -> Reader {
+        let output = Arc::new(Mutex::new(String::new()));
+        let collected = Arc::clone(&output);
+        let thread = thread::spawn(move || {
+            let mut pipe = match pipe {
+                Some(pipe) => BufReader::new(pipe),
+                None => return,
+            };
+            let mut line = Vec::new();
+            while let Ok(read) = pipe.read_until(b'\n', &mut line) {
+                if read == 0 {
+                    break;
+                }
+                let text = String::from_utf8_lossy(&line);
+                on_line(stream, text.trim_end_matches(['\n', '\r']));
+                collected
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push_str(&text);
+                line.clear();
+            }
+        });
+        Reader { output, thread }
+    }
+
+    /// Whether the pipe was closed and all of it read
+    fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// What was read so far
+    fn output(&self) -> String {
+        self.output
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod process_tests {
+    use super::*;
+
+    fn quiet() -> OnLine {
+        Arc::new(|_, _| {})
+    }
+
+    #[test]
+    fn test_run() -> Result<()> {
+        let mut envs = Envs::new();
+        envs.insert(String::from("GREETING"), String::from("hello"));
+        let exit = run(
+            "echo $GREETING; echo oops >&2; exit 3",
+            &envs,
+            Path::new("."),
+            Some(Duration::from_secs(10)),
+            quiet(),
+            &mut || false,
+        )?;
+        assert_eq!(
+            exit,
+            Exit::Code(3, String::from("hello\n"), String::from("oops\n"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_timeout_kills_the_group() -> Result<()> {
+        let marker = crate::testing::temp_path();
+        let command = format!(
+            "echo started; (sleep 2; touch {}) & sleep 30",
+            marker.display()
+        );
+
+        let started = Instant::now();
+        let exit = run(
+            &command,
+            &Envs::new(),
+            Path::new("."),
+            Some(Duration::from_secs(1)),
+            quiet(),
+            &mut || false,
+        )?;
+        assert_eq!(
+            exit,
+            Exit::TimedOut(String::from("started\n"), String::new())
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // The backgrounded child went down with the command
+        thread::sleep(Duration::from_secs(2));
+        assert!(!marker.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_timeout_with_pipes_held_open() -> Result<()> {
+        // The shell exits at once, but leaves a child holding its stdout
+        let started = Instant::now();
+        let exit = run(
+            "sleep 30 &",
+            &Envs::new(),
+            Path::new("."),
+            Some(Duration::from_secs(1)),
+            quiet(),
+            &mut || false,
+        )?;
+        assert_eq!(exit, Exit::TimedOut(String::new(), String::new()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_streams_lines() -> Result<()> {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&lines);
+        let on_line: OnLine = Arc::new(move |stream, line: &str| {
+            seen.lock().unwrap().push((stream, line.to_string()));
+        });
+
+        let exit = run(
+            "echo one; sleep 1; echo two >&2",
+            &Envs::new(),
+            Path::new("."),
+            None,
+            on_line,
+            &mut || false,
+        )?;
+        assert_eq!(
+            exit,
+            Exit::Code(0, String::from("one\n"), String::from("two\n"))
+        );
+        assert_eq!(
+            *lines.lock().unwrap(),
+            vec![
+                (Stream::Stdout, String::from("one")),
+                (Stream::Stderr, String::from("two"))
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_cancel() -> Result<()> {
+        let started = Instant::now();
+        let mut cancel = || started.elapsed() > Duration::from_secs(1);
+        let exit = run(
+            "echo waiting >&2; sleep 30",
+            &Envs::new(),
+            Path::new("."),
+            None,
+            quiet(),
+            &mut cancel,
+        )?;
+        assert_eq!(
+            exit,
+            Exit::Cancelled(String::new(), String::from("waiting\n"))
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use uuid::Uuid;
+
+/// How many times, and how soon, a failed command is run again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// Attempts after the first, 0 never retries
+    pub count: u32,
+    /// Seconds before the first retry
+    pub delay: f64,
+    /// What each wait is multiplied by for the next retry
+    pub backoff: f64,
+    /// The fraction of each wait randomly added or taken away, from 0 to 1
+    pub jitter: f64,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            count: 0,
+            delay: 10.0,
+            backoff: 2.0,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl Retry {
+    pub fn new(count: u32, delay: f64, backoff: f64, jitter: f64) -> Result<Retry> {
+        if !(delay >= 0.0 && delay.is_finite()) {
+            return Err(invalid(format!("retry delay {} must be 0 or more", delay)));
+        }
+        if !(backoff >= 1.0 && backoff.is_finite()) {
+            return Err(invalid(format!(
+                "retry backoff {} must be 1 or more",
+                backoff
+            )));
+        }
+        if !(0.0..=1.0).contains(&jitter) {
+            return Err(invalid(format!(
+                "retry jitter {} must be from 0 to 1",
+                jitter
+            )));
+        }
+        Ok(Retry {
+            count,
+            delay,
+            backoff,
+            jitter,
+        })
+    }
+
+    /// How long to wait before the given retry, counting from 1, without
+    /// the jitter
+    fn base_wait(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        Duration::from_secs_f64((self.delay * self.backoff.powi(exponent)).min(u32::MAX as f64))
+    }
+
+    /// How long to wait before the given retry, counting from 1
+    pub fn wait(&self, retry: u32) -> Duration {
+        // A v4 uuid is random enough to spread out retries
+        let random = Uuid::new_v4().as_u128() as f64 / u128::MAX as f64;
+        self.base_wait(retry)
+            .mul_f64(1.0 + self.jitter * (2.0 * random - 1.0))
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod retry_tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let retry = Retry::new(3, 5.0, 2.0, 0.0).unwrap();
+        assert_eq!(retry.wait(1), Duration::from_secs(5));
+        assert_eq!(retry.wait(2), Duration::from_secs(10));
+        assert_eq!(retry.wait(3), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let retry = Retry::new(1, 10.0, 1.0, 0.5).unwrap();
+        for _ in 0..100 {
+            let wait = retry.wait(1);
+            assert!(wait >= Duration::from_secs(5) && wait <= Duration::from_secs(15));
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Retry::new(1, -1.0, 2.0, 0.0).is_err());
+        assert!(Retry::new(1, 1.0, 0.5, 0.0).is_err());
+        assert!(Retry::new(1, 1.0, 2.0, 1.5).is_err());
+    }
+}
@@ -1,41 +1,154 @@
 use std::env::temp_dir;
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use url::Url;
 use uuid::Uuid;
 
+use crate::config;
 use crate::repos::Repo;
 
+pub mod reload;
+pub mod supervisor;
+
+use reload::ConfigWatcher;
+use supervisor::Supervisor;
+
 pub fn spy_repo(mut repo: Repo) -> Result<()> {
     if repo.verbosity > 0 {
-        info!("starting to spy {}:{}", repo.url, repo.branch);
+        info!("starting to spy {}:{}", repo.url, repo.branch_names());
+    }
+
+    if let Err(e) = prepare_repo(&mut repo) {
+        eprintln!("goa error: {}", e);
+        std::process::exit(1);
+    }
+
+    // Clone the repo and set the local path
+    if let Err(e) = repo
+        .clone_repo()
+        .and_then(|_| repo.track_branches())
+        .and_then(|_| repo.resume())
+    {
+        eprintln!("goa error: {}", e);
+        std::process::exit(1);
+    }
+    repo.status = Some(String::from("cloned"));
+
+    // This is where the loop happens...
+    repo.spy_for_changes();
+
+    Ok(())
+}
+
+/// Spy every repo from a single agent, sharing a limited set of workers, and
+/// reconcile the watchers whenever the config changes or on SIGHUP
+pub fn run_config(
+    config_path: PathBuf,
+    repos: Vec<Repo>,
+    workers: usize,
+    verbosity: u8,
+) -> Result<()> {
+    let mut supervisor = Supervisor::new(repos, workers)?;
+    let mut config_watcher = ConfigWatcher::new(config_path)?;
+    let manual = manual_trigger()?;
+
+    loop {
+        supervisor.step()?;
+
+        if manual.swap(false, Ordering::Relaxed) {
+            info!("SIGUSR1 received, running every repo's command");
+            supervisor.run_all();
+        }
+
+        if config_watcher.changed() {
+            let path = config_watcher.path();
+            match config::load(path).and_then(|c| c.into_repos(verbosity)) {
+                Ok(repos) => {
+                    info!("reloading {}", path.display());
+                    supervisor.reload(repos);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "goa error: keeping the running config, {} is invalid: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// A flag raised whenever the agent receives a SIGUSR1, asking for every
+/// command to run now
+pub fn manual_trigger() -> Result<Arc<AtomicBool>> {
+    let manual = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&manual))?;
+    Ok(manual)
+}
+
+/// A name for a lone spy, telling it apart from others spying the same repo,
+/// as a config's repo names do: the repo, what it watches, and a digest of
+/// where it clones to and what it runs. It keys the spy's state, so it has to
+/// stay the same across restarts.
+pub fn watcher_name(repo: &Repo) -> String {
+    // FNV-1a, which unlike std's hasher is fixed for good
+    let mut digest: u32 = 0x811c_9dc5;
+    let target_path = repo.local_path.as_deref().unwrap_or_default();
+    for byte in [target_path, "\0", &repo.command].concat().bytes() {
+        digest = (digest ^ byte as u32).wrapping_mul(0x0100_0193);
     }
+    format!(
+        "{}:{}@{:08x}",
+        public_url(&repo.url),
+        repo.branch_names(),
+        digest
+    )
+}
 
+/// The url without the credentials `prepare_repo` put into it, fit to show
+pub fn public_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed_url) => {
+            let _ = parsed_url.set_username("");
+            let _ = parsed_url.set_password(None);
+            parsed_url.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Put the credentials into the url and pick a local path for the clone
+pub fn prepare_repo(repo: &mut Repo) -> Result<()> {
     repo.url = match Url::parse(&repo.url) {
         Ok(mut parsed_url) => {
             if let Some(ref username) = repo.username {
                 if let Err(e) = parsed_url.set_username(username) {
-                    eprintln!("goa error: {:?}", e);
-                    std::process::exit(1);
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("{:?}", e)));
                 };
             }
 
             if let Some(ref token) = repo.token {
                 let token_str: &str = &token[..];
                 if let Err(e) = parsed_url.set_password(Option::from(token_str)) {
-                    eprintln!("goa error: {:?}", e);
-                    std::process::exit(1);
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("{:?}", e)));
                 };
             }
             parsed_url.to_string()
         }
         Err(e) => {
-            eprintln!("goa error: invalid URL or path, {}", e);
-            std::process::exit(1);
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid URL or path, {}", e),
+            ));
         }
     };
 
-    if repo.local_path == None {
+    if repo.local_path.is_none() {
         // Get a temp directory to do work in
         let temp_dir = temp_dir();
         let mut local_path: String = temp_dir.into_os_string().into_string().unwrap();
@@ -46,12 +159,6 @@ pub fn spy_repo(mut repo: Repo) -> Result<()> {
         repo.local_path = Some(local_path);
     }
 
-    // Clone the repo and set the local path
-    repo.clone_repo();
-
-    // This is where the loop happens...
-    repo.spy_for_changes();
-
     Ok(())
 }
 
@@ -0,0 +1,77 @@
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Notices when the agent config should be read again, either because the
+/// file was modified or because the agent received a SIGHUP
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    hangup: Arc<AtomicBool>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> Result<ConfigWatcher> {
+        let hangup = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&hangup))?;
+
+        Ok(ConfigWatcher {
+            modified: modified(&path),
+            path,
+            hangup,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// True once per SIGHUP, and once per change of the config's mtime
+    pub fn changed(&mut self) -> bool {
+        let hangup = self.hangup.swap(false, Ordering::Relaxed);
+        let modified = modified(&self.path);
+        if modified != self.modified {
+            self.modified = modified;
+            return true;
+        }
+        hangup
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod reload_tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_changed_on_modify() {
+        let path = testing::temp_path();
+        std::fs::write(&path, "repos = []\n").unwrap();
+        let mut watcher = ConfigWatcher::new(path.clone()).unwrap();
+        assert!(!watcher.changed());
+
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(later).unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+    }
+
+    #[test]
+    fn test_changed_on_hangup() {
+        let path = testing::temp_path();
+        std::fs::write(&path, "repos = []\n").unwrap();
+        let mut watcher = ConfigWatcher::new(path).unwrap();
+
+        watcher.hangup.store(true, Ordering::Relaxed);
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::BTreeMap;
+use std::io::{Error, Result};
+use std::ops::DerefMut;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::repos::{self, Repo, Trigger};
+use crate::spy;
+
+/// How long the supervisor waits, at most, before looking for due watchers again
+const IDLE_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Task {
+    /// Prepare, clone and (optionally) exec on start
+    Start,
+    /// A single fetch/diff/exec cycle
+    Tick,
+    /// Run the commands now, whether or not anything changed
+    Manual,
+}
+
+struct Job {
+    id: u64,
+    name: String,
+    task: Task,
+    repo: Arc<Mutex<Repo>>,
+}
+
+struct Done {
+    id: u64,
+    name: String,
+    task: Task,
+    ok: bool,
+}
+
+struct Watcher {
+    /// Tells a replaced watcher's late reports apart from its successor's
+    id: u64,
+    /// The repo as configured, before credentials and a clone path were added
+    spec: Repo,
+    /// A new configuration waiting for the current job to finish
+    pending: Option<Repo>,
+    repo: Arc<Mutex<Repo>>,
+    delay: Duration,
+    started: bool,
+    running: bool,
+    /// A manual run waiting for the current job to finish
+    manual: bool,
+    next_run: Instant,
+}
+
+impl Watcher {
+    fn new(id: u64, spec: Repo) -> Watcher {
+        Watcher {
+            id,
+            delay: Duration::from_secs(spec.delay as u64),
+            repo: Arc::new(Mutex::new(spec.clone())),
+            spec,
+            pending: None,
+            started: false,
+            running: false,
+            manual: false,
+            next_run: Instant::now(),
+        }
+    }
+
+    /// Apply a waiting configuration to the live repo, keeping its clone
+    fn reconfigure(&mut self) {
+        let spec = match self.pending.take() {
+            Some(spec) => spec,
+            None => return,
+        };
+        let mut repo = self.repo.lock().unwrap_or_else(PoisonError::into_inner);
+        repo.branches = spec.branches.clone();
+        repo.tag = spec.tag.clone();
+        repo.paths = spec.paths.clone();
+        repo.commits = spec.commits.clone();
+        repo.trust = spec.trust.clone();
+        repo.monorepo = spec.monorepo;
+        repo.per_commit = spec.per_commit;
+        repo.depth = spec.depth;
+        repo.submodules = spec.submodules;
+        repo.state_dir = spec.state_dir.clone();
+        repo.command = spec.command.clone();
+        repo.hooks = spec.hooks.clone();
+        repo.timeout = spec.timeout;
+        repo.failure_policy = spec.failure_policy;
+        repo.retry = spec.retry;
+        repo.queue_policy = spec.queue_policy;
+        // A changed config is the way out of a pause
+        if repo.is_paused() {
+            repo.status = Some(String::from("cloned"));
+        }
+        repo.delay = spec.delay;
+        repo.verbosity = spec.verbosity;
+        repo.exec_on_start = spec.exec_on_start;
+        self.delay = Duration::from_secs(spec.delay as u64);
+        self.next_run = Instant::now() + self.delay;
+        self.spec = spec;
+    }
+}
+
+/// Whether two configurations can share a clone
+fn same_remote(a: &Repo, b: &Repo) -> bool {
+    a.url == b.url
+        && a.username == b.username
+        && a.token == b.token
+        && a.local_path == b.local_path
+        && a.single_branch == b.single_branch
+        && a.sparse == b.sparse
+}
+
+/// Owns a set of repo watchers and runs their cycles on a limited pool of
+/// workers, so a slow or failing repo only ever ties up its own worker
+pub struct Supervisor {
+    watchers: BTreeMap<String, Watcher>,
+    next_id: u64,
+    jobs: Sender<Job>,
+    done: Receiver<Done>,
+}
+
+impl Supervisor {
+    pub fn new(repos: Vec<Repo>, workers: usize) -> Result<Supervisor> {
+        let (jobs, job_queue) = mpsc::channel::<Job>();
+        let job_queue = Arc::new(Mutex::new(job_queue));
+        let (done_tx, done) = mpsc::channel::<Done>();
+
+        for i in 0..workers.max(1) {
+            let job_queue = Arc::clone(&job_queue);
+            let done_tx = done_tx.clone();
+            thread::Builder::new()
+                .name(format!("goa-worker-{}", i))
+                .spawn(move || work(job_queue, done_tx))?;
+        }
+
+        let mut supervisor = Supervisor {
+            watchers: BTreeMap::new(),
+            next_id: 0,
+            jobs,
+            done,
+        };
+        for repo in repos {
+            supervisor.add(repo);
+        }
+        Ok(supervisor)
+    }
+
+    fn add(&mut self, spec: Repo) {
+        self.next_id += 1;
+        self.watchers
+            .insert(spec.name.clone(), Watcher::new(self.next_id, spec));
+    }
+
+    /// Bring the set of watchers in line with a freshly loaded config.
+    /// Watchers whose remote and clone path are unchanged keep their clone,
+    /// and pick up the rest of their new configuration once they are idle.
+    pub fn reload(&mut self, repos: Vec<Repo>) {
+        let incoming: BTreeMap<String, Repo> =
+            repos.into_iter().map(|r| (r.name.clone(), r)).collect();
+
+        self.watchers.retain(|name, _| {
+            let keep = incoming.contains_key(name);
+            if !keep {
+                info!("{}: removed from config, no longer spying", name);
+            }
+            keep
+        });
+
+        for (name, spec) in incoming {
+            match self.watchers.get_mut(&name) {
+                Some(watcher) if watcher.spec == spec => {}
+                Some(watcher) if same_remote(&watcher.spec, &spec) => {
+                    info!("{}: reconfigured, keeping the existing clone", name);
+                    watcher.pending = Some(spec);
+                }
+                Some(_) => {
+                    info!("{}: remote or target path changed, cloning again", name);
+                    self.add(spec);
+                }
+                None => {
+                    info!("{}: added to config, starting to spy", name);
+                    self.add(spec);
+                }
+            }
+        }
+    }
+
+    /// Have every started watcher run its commands as soon as it's idle
+    pub fn run_all(&mut self) {
+        for watcher in self.watchers.values_mut() {
+            watcher.manual = watcher.started;
+        }
+    }
+
+    /// Dispatch every due watcher, then wait for a worker to report back or
+    /// for the next watcher to come due
+    pub fn step(&mut self) -> Result<()> {
+        for watcher in self.watchers.values_mut() {
+            if !watcher.running {
+                watcher.reconfigure();
+            }
+        }
+
+        let now = Instant::now();
+        for (name, watcher) in self.watchers.iter_mut() {
+            if watcher.running || (watcher.next_run > now && !watcher.manual) {
+                continue;
+            }
+            watcher.running = true;
+            let task = if !watcher.started {
+                Task::Start
+            } else if watcher.manual {
+                Task::Manual
+            } else {
+                Task::Tick
+            };
+            if task == Task::Manual {
+                watcher.manual = false;
+            } else {
+                watcher.next_run = now + watcher.delay;
+            }
+            let job = Job {
+                id: watcher.id,
+                name: name.clone(),
+                task,
+                repo: Arc::clone(&watcher.repo),
+            };
+            if self.jobs.send(job).is_err() {
+                return Err(Error::other("all spy workers have stopped"));
+            }
+        }
+
+        let wait = self
+            .watchers
+            .values()
+            .filter(|w| !w.running)
+            .map(|w| w.next_run.saturating_duration_since(now))
+            .min()
+            .unwrap_or(IDLE_WAIT)
+            .min(IDLE_WAIT);
+
+        match self.done.recv_timeout(wait) {
+            Ok(done) => {
+                match self.watchers.get_mut(&done.name) {
+                    Some(watcher) if watcher.id == done.id => {
+                        watcher.running = false;
+                        if done.task == Task::Start && done.ok {
+                            watcher.started = true;
+                        }
+                    }
+                    _ => {}
+                }
+                Ok(())
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::other("all spy workers have stopped"))
+            }
+        }
+    }
+}
+
+fn work(job_queue: Arc<Mutex<Receiver<Job>>>, done: Sender<Done>) {
+    loop {
+        let job = match job_queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv()
+        {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let ok = run_job(&job);
+        let done_msg = Done {
+            id: job.id,
+            name: job.name,
+            task: job.task,
+            ok,
+        };
+        if done.send(done_msg).is_err() {
+            return;
+        }
+    }
+}
+
+fn run_job(job: &Job) -> bool {
+    let mut repo = job.repo.lock().unwrap_or_else(PoisonError::into_inner);
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| match job.task {
+        Task::Start => start(repo.deref_mut()),
+        Task::Tick => repos::do_process(repo.deref_mut()),
+        Task::Manual => repos::do_process_once(repo.deref_mut(), Trigger::Manual),
+    }));
+
+    let failure = match outcome {
+        Ok(Ok(())) => {
+            // Back on track once a job goes through again
+            if repo.status.as_deref() == Some("failed") {
+                repo.status = Some(String::from("cloned"));
+            }
+            return true;
+        }
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => String::from("spy panicked"),
+    };
+    repo.status = Some(String::from("failed"));
+    eprintln!(
+        "goa error: {}: {}, trying again in {} seconds",
+        job.name, failure, repo.delay
+    );
+    false
+}
+
+fn start(repo: &mut Repo) -> Result<()> {
+    if repo.verbosity > 0 {
+        info!("starting to spy {}:{}", repo.url, repo.branch_names());
+    }
+
+    spy::prepare_repo(repo)?;
+    repo.clone_repo()?;
+    repo.track_branches()?;
+    repo.resume()?;
+    repo.status = Some(String::from("cloned"));
+
+    if repo.exec_on_start {
+        repos::do_process_once(repo, Trigger::Start)?;
+        if repo.verbosity > 0 {
+            info!("{}: exec on startup complete", repo.name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod supervisor_tests {
+    use super::*;
+    use crate::testing;
+    use std::path::Path;
+
+    fn test_repo(name: &str, url: String) -> Repo {
+        let mut repo = Repo::new(
+            url,
+            None,
+            None,
+            Some(String::from("initialize")),
+            None,
+            String::from("main"),
+            String::from("echo hello"),
+            1,
+            0,
+            false,
+            false,
+        );
+        repo.name = String::from(name);
+        repo
+    }
+
+    fn step_until(supervisor: &mut Supervisor, done: impl Fn(&Supervisor) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !done(supervisor) {
+            assert!(Instant::now() < deadline, "supervisor never settled");
+            supervisor.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_failing_repo_does_not_stop_others() {
+        let (_upstream, url) = testing::init_upstream();
+        let missing = format!("file://{}", testing::temp_path().display());
+        let repos = vec![test_repo("good", url), test_repo("bad", missing)];
+        let mut supervisor = Supervisor::new(repos, 2).unwrap();
+
+        step_until(&mut supervisor, |s| {
+            s.watchers["good"].started && !s.watchers["good"].running
+        });
+        step_until(&mut supervisor, |s| {
+            !s.watchers["bad"].running && s.watchers["bad"].next_run > Instant::now()
+        });
+
+        let bad = &supervisor.watchers["bad"];
+        assert!(!bad.started);
+        assert_eq!(
+            bad.repo.lock().unwrap().status,
+            Some(String::from("failed"))
+        );
+        let good = &supervisor.watchers["good"];
+        assert_eq!(
+            good.repo.lock().unwrap().status,
+            Some(String::from("cloned"))
+        );
+    }
+
+    #[test]
+    fn test_failed_start_recovers() {
+        let path = testing::temp_path();
+        let url = format!("file://{}", path.display());
+        let mut supervisor = Supervisor::new(vec![test_repo("late", url)], 1).unwrap();
+        step_until(&mut supervisor, |s| {
+            !s.watchers["late"].running && s.watchers["late"].next_run > Instant::now()
+        });
+        let local_path = supervisor.watchers["late"]
+            .repo
+            .lock()
+            .unwrap()
+            .local_path
+            .clone()
+            .unwrap();
+        assert!(!Path::new(&local_path).exists());
+
+        // Once the remote shows up, the next start clones it
+        let upstream = git2::Repository::init(&path).unwrap();
+        upstream.set_head("refs/heads/main").unwrap();
+        testing::commit_file(&upstream, "README.md", "goa test repo\n", "initial commit");
+        step_until(&mut supervisor, |s| s.watchers["late"].started);
+        assert_eq!(
+            supervisor.watchers["late"].repo.lock().unwrap().status,
+            Some(String::from("cloned"))
+        );
+    }
+
+    #[test]
+    fn test_ticks_after_start() {
+        let (upstream, url) = testing::init_upstream();
+        let mut supervisor = Supervisor::new(vec![test_repo("good", url)], 1).unwrap();
+
+        step_until(&mut supervisor, |s| s.watchers["good"].started);
+        testing::commit_file(&upstream, "README.md", "changed\n", "second commit");
+        let head = upstream.head().unwrap().target().unwrap().to_string();
+
+        step_until(&mut supervisor, |s| {
+            s.watchers["good"]
+                .repo
+                .lock()
+                .unwrap()
+                .envs
+                .get("GOA_LAST_COMMIT_ID")
+                == Some(&head)
+        });
+    }
+
+    #[test]
+    fn test_run_all() {
+        let (_upstream, url) = testing::init_upstream();
+        let mut repo = test_repo("good", url);
+        repo.delay = 300;
+        let mut supervisor = Supervisor::new(vec![repo], 1).unwrap();
+        step_until(&mut supervisor, |s| {
+            s.watchers["good"].started && !s.watchers["good"].running
+        });
+
+        supervisor.run_all();
+        step_until(&mut supervisor, |s| {
+            s.watchers["good"]
+                .repo
+                .lock()
+                .unwrap()
+                .envs
+                .get("GOA_TRIGGER")
+                .map(String::as_str)
+                == Some("manual")
+        });
+        assert!(!supervisor.watchers["good"].manual);
+    }
+
+    #[test]
+    fn test_reload_keeps_clone() {
+        let (_upstream, url) = testing::init_upstream();
+        let mut supervisor = Supervisor::new(vec![test_repo("first", url.clone())], 1).unwrap();
+        step_until(&mut supervisor, |s| s.watchers["first"].started);
+        let first_id = supervisor.watchers["first"].id;
+        let clone_path = supervisor.watchers["first"]
+            .repo
+            .lock()
+            .unwrap()
+            .local_path
+            .clone();
+
+        let mut reconfigured = test_repo("first", url.clone());
+        reconfigured.command = String::from("echo reloaded");
+        reconfigured.delay = 5;
+        supervisor.reload(vec![reconfigured, test_repo("second", url)]);
+        step_until(&mut supervisor, |s| s.watchers["second"].started);
+
+        let first = &supervisor.watchers["first"];
+        assert_eq!(first.id, first_id);
+        assert_eq!(first.delay, Duration::from_secs(5));
+        let repo = first.repo.lock().unwrap();
+        assert_eq!(repo.local_path, clone_path);
+        assert_eq!(repo.command, "echo reloaded");
+    }
+
+    #[test]
+    fn test_reload_removes_and_reclones() {
+        let (_upstream, url) = testing::init_upstream();
+        let (_other, other_url) = testing::init_upstream();
+        let repos = vec![test_repo("first", url.clone()), test_repo("second", url)];
+        let mut supervisor = Supervisor::new(repos, 2).unwrap();
+        let second_id = supervisor.watchers["second"].id;
+
+        supervisor.reload(vec![test_repo("second", other_url)]);
+
+        assert!(!supervisor.watchers.contains_key("first"));
+        assert_ne!(supervisor.watchers["second"].id, second_id);
+        step_until(&mut supervisor, |s| s.watchers["second"].started);
+    }
+}
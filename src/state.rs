@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// What a watcher last deployed, kept outside its clone so it outlives
+/// restarts of the agent
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    /// The repo the commits belong to, without credentials
+    pub url: String,
+    /// The last commit a command ran successfully for, by ref name, e.g.
+    /// `refs/heads/main` or `refs/tags/v1.0.0`
+    #[serde(default)]
+    pub deployed: BTreeMap<String, String>,
+}
+
+/// Where state is kept when neither the command line nor the config say,
+/// following the XDG base directories
+pub fn default_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("goa")),
+        _ => std::env::var_os("HOME")
+            .filter(|home| !home.is_empty())
+            .map(|home| PathBuf::from(home).join(".local/state/goa")),
+    }
+}
+
+/// The state file of the watcher with this name
+pub fn path(state_dir: &Path, name: &str) -> PathBuf {
+    let file_name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    state_dir.join(format!("{}.toml", file_name))
+}
+
+/// Read the state of a watcher, starting afresh when there's none yet or
+/// it belongs to another repo
+pub fn load(state_dir: &Path, name: &str, url: &str) -> Result<State> {
+    let path = path(state_dir, name);
+    let state: State = match std::fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents).map_err(|e| {
+            Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        })?,
+        Err(e) if e.kind() == ErrorKind::NotFound => State::default(),
+        Err(e) => return Err(e),
+    };
+    if state.url != url {
+        return Ok(State {
+            url: url.to_string(),
+            ..State::default()
+        });
+    }
+    Ok(state)
+}
+
+/// Write the state of a watcher, replacing the file in one go so a crash
+/// never leaves half of it behind
+pub fn save(state_dir: &Path, name: &str, state: &State) -> Result<()> {
+    std::fs::create_dir_all(state_dir)?;
+    let path = path(state_dir, name);
+    let contents = toml::to_string(state).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let temp_path = path.with_extension("toml.tmp");
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(&temp_path, &path)
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_save_and_load() -> Result<()> {
+        let state_dir = testing::temp_path();
+        let url = "https://github.com/kitplummer/goa_tester";
+        assert_eq!(
+            load(&state_dir, "tester", url)?,
+            State {
+                url: url.to_string(),
+                ..State::default()
+            }
+        );
+
+        let mut state = load(&state_dir, "tester", url)?;
+        state
+            .deployed
+            .insert(String::from("refs/heads/main"), String::from("4b8e"));
+        save(&state_dir, "tester", &state)?;
+        assert_eq!(load(&state_dir, "tester", url)?, state);
+
+        // Another repo under the same name starts afresh
+        let other = load(&state_dir, "tester", "https://github.com/kitplummer/goa")?;
+        assert!(other.deployed.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            path(
+                Path::new("/var/lib/goa"),
+                "https://github.com/kitplummer/goa_tester:main"
+            ),
+            PathBuf::from("/var/lib/goa/https___github.com_kitplummer_goa_tester_main.toml")
+        );
+    }
+}
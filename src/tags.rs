@@ -0,0 +1,116 @@
+use semver::{Version, VersionReq};
+
+/// Tags of the repo to spy, and what to run when a new one appears
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagWatch {
+    /// A glob such as `v*`
+    pub pattern: String,
+    /// Only tags whose version satisfies this range fire, e.g. `>=2.0, <3`
+    pub semver: Option<VersionReq>,
+    /// Empty falls back to the repo's command, then the tag's .goa file
+    pub command: String,
+}
+
+impl TagWatch {
+    pub fn new(pattern: String, semver: Option<VersionReq>, command: String) -> TagWatch {
+        TagWatch {
+            pattern,
+            semver,
+            command,
+        }
+    }
+
+    /// Whether a tag matching the pattern also satisfies the semver range
+    pub fn allows(&self, tag: &str) -> bool {
+        match self.semver {
+            Some(ref req) => match tag_version(tag) {
+                Some(version) => req.matches(&version),
+                None => false,
+            },
+            None => true,
+        }
+    }
+
+    /// The highest allowed tag, by version when the tags have one and by name
+    /// otherwise
+    pub fn newest<'a, I>(&self, tags: I) -> Option<String>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        tags.into_iter()
+            .filter(|tag| self.allows(tag))
+            .max_by(|a, b| (tag_version(a), a).cmp(&(tag_version(b), b)))
+            .cloned()
+    }
+}
+
+/// The version a tag names, ignoring any prefix before the first digit
+/// (`v2.1.0`, `release-2.1`) and filling in a missing minor or patch
+pub fn tag_version(tag: &str) -> Option<Version> {
+    let start = tag.find(|c: char| c.is_ascii_digit())?;
+    let version = &tag[start..];
+    if let Ok(version) = Version::parse(version) {
+        return Some(version);
+    }
+
+    let (core, rest) = match version.find(['-', '+']) {
+        Some(i) => version.split_at(i),
+        None => (version, ""),
+    };
+    let padded = match core.split('.').count() {
+        1 => format!("{}.0.0{}", core, rest),
+        2 => format!("{}.0{}", core, rest),
+        _ => return None,
+    };
+    Version::parse(&padded).ok()
+}
+
+#[cfg(test)]
+mod tags_tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_version() {
+        assert_eq!(tag_version("v2.1.3"), Some(Version::new(2, 1, 3)));
+        assert_eq!(tag_version("release-2.1"), Some(Version::new(2, 1, 0)));
+        assert_eq!(tag_version("v3"), Some(Version::new(3, 0, 0)));
+        assert_eq!(
+            tag_version("v2.0.0-rc.1"),
+            Some(Version::parse("2.0.0-rc.1").unwrap())
+        );
+        assert_eq!(tag_version("latest"), None);
+    }
+
+    #[test]
+    fn test_allows_semver_range() {
+        let watch = TagWatch::new(
+            String::from("v*"),
+            Some(VersionReq::parse(">=2.0, <3").unwrap()),
+            String::new(),
+        );
+        assert!(watch.allows("v2.0.0"));
+        assert!(watch.allows("v2.9"));
+        assert!(!watch.allows("v1.9.9"));
+        assert!(!watch.allows("v3.0.0"));
+        assert!(!watch.allows("vlatest"));
+    }
+
+    #[test]
+    fn test_newest() {
+        let watch = TagWatch::new(String::from("v*"), None, String::new());
+        let tags = vec![
+            String::from("v1.10.0"),
+            String::from("v1.9.0"),
+            String::from("v1.2.0"),
+        ];
+        assert_eq!(watch.newest(&tags), Some(String::from("v1.10.0")));
+
+        let watch = TagWatch::new(
+            String::from("v*"),
+            Some(VersionReq::parse("<1.5").unwrap()),
+            String::new(),
+        );
+        assert_eq!(watch.newest(&tags), Some(String::from("v1.2.0")));
+        assert_eq!(watch.newest(&vec![]), None);
+    }
+}
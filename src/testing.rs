@@ -0,0 +1,141 @@
+// Helpers for tests that need a local "remote" repo to spy on
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use git2::{Oid, Repository, Signature};
+use uuid::Uuid;
+
+/// A unique, not yet existing, path under the temp dir
+pub fn temp_path() -> PathBuf {
+    std::env::temp_dir().join(Uuid::new_v4().to_string())
+}
+
+/// Create a repo with an initial commit on `main`, returning it and its file:// url
+pub fn init_upstream() -> (Repository, String) {
+    let path = temp_path();
+    let repo = Repository::init(&path).expect("Couldn't init repository");
+    repo.set_head("refs/heads/main").unwrap();
+    commit_file(&repo, "README.md", "goa test repo\n", "initial commit");
+    let url = format!("file://{}", path.display());
+    (repo, url)
+}
+
+/// Write a file into the work dir of `repo` and commit it onto HEAD
+pub fn commit_file(repo: &Repository, path: &str, contents: &str, message: &str) -> Oid {
+    let workdir = repo.workdir().unwrap();
+    let file_path = workdir.join(path);
+    if let Some(parent) = file_path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    std::fs::write(&file_path, contents).unwrap();
+
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("Kit Plummer", "kitplummer@gmail.com").unwrap();
+    let parents = match repo.head().ok().and_then(|h| h.peel_to_commit().ok()) {
+        Some(parent) => vec![parent],
+        None => vec![],
+    };
+    let parents: Vec<_> = parents.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )
+    .unwrap()
+}
+
+/// Add `url` as a submodule of `repo` at `path`, and commit it
+pub fn add_submodule(repo: &Repository, url: &str, path: &str) -> Oid {
+    let mut submodule = repo.submodule(url, Path::new(path), true).unwrap();
+    submodule.clone(None).unwrap();
+    submodule.add_finalize().unwrap();
+    commit_index(repo, &format!("add {}", path))
+}
+
+/// Move the submodule at `path` to the tip of its remote's main, and commit
+/// the new pointer
+pub fn bump_submodule(repo: &Repository, path: &str) -> Oid {
+    let mut submodule = repo.find_submodule(path).unwrap();
+    let sub_repo = submodule.open().unwrap();
+    sub_repo
+        .find_remote("origin")
+        .unwrap()
+        .fetch(&["+refs/heads/main:refs/remotes/origin/main"], None, None)
+        .unwrap();
+    let tip = sub_repo.refname_to_id("refs/remotes/origin/main").unwrap();
+    sub_repo.set_head_detached(tip).unwrap();
+    submodule.add_to_index(true).unwrap();
+    commit_index(repo, &format!("bump {}", path))
+}
+
+fn commit_index(repo: &Repository, message: &str) -> Oid {
+    let mut index = repo.index().unwrap();
+    index.read(true).unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("Kit Plummer", "kitplummer@gmail.com").unwrap();
+    let parent = repo.head().unwrap().peel_to_commit().unwrap();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &[&parent],
+    )
+    .unwrap()
+}
+
+/// An SSH key, and an allowed signers file trusting it
+pub fn ssh_key() -> (PathBuf, PathBuf) {
+    let dir = temp_path();
+    std::fs::create_dir_all(&dir).unwrap();
+    let key = dir.join("id_ed25519");
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let public = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+    let allowed_signers = dir.join("allowed_signers");
+    std::fs::write(&allowed_signers, format!("kitplummer@gmail.com {}", public)).unwrap();
+    (key, allowed_signers)
+}
+
+/// Commit on HEAD, signed with the SSH key
+pub fn commit_signed(repo: &Repository, key: &Path, message: &str) -> Oid {
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    let signature = Signature::now("Kit Plummer", "kitplummer@gmail.com").unwrap();
+    let buffer = repo
+        .commit_create_buffer(
+            &signature,
+            &signature,
+            message,
+            &head.tree().unwrap(),
+            &[&head],
+        )
+        .unwrap();
+    let data = temp_path();
+    std::fs::write(&data, &*buffer).unwrap();
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-Y", "sign", "-n", "git", "-f"])
+        .arg(key)
+        .arg(&data)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let sig = std::fs::read_to_string(data.with_extension("sig")).unwrap();
+
+    let oid = repo
+        .commit_signed(buffer.as_str().unwrap(), &sig, None)
+        .unwrap();
+    repo.reference("refs/heads/main", oid, true, "signed commit")
+        .unwrap();
+    oid
+}
@@ -0,0 +1,273 @@
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use git2::{Commit, Oid, Repository};
+use uuid::Uuid;
+
+/// Which new commits must carry a trusted signature before they are merged
+/// and run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verify {
+    Off,
+    /// Only the new tip of a branch, or a new tag's commit
+    Tip,
+    /// Every commit between the old and the new tip
+    All,
+}
+
+impl FromStr for Verify {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Verify, String> {
+        match s {
+            "off" => Ok(Verify::Off),
+            "tip" => Ok(Verify::Tip),
+            "all" => Ok(Verify::All),
+            _ => Err(format!("{} isn't one of off, tip or all", s)),
+        }
+    }
+}
+
+/// The signatures goa trusts: GPG signatures by keys in the keyring, and SSH
+/// signatures by keys in the allowed signers file
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustPolicy {
+    pub verify: Verify,
+    pub gpg_keyring: Option<PathBuf>,
+    pub allowed_signers: Option<PathBuf>,
+}
+
+impl Default for TrustPolicy {
+    fn default() -> TrustPolicy {
+        TrustPolicy {
+            verify: Verify::Off,
+            gpg_keyring: None,
+            allowed_signers: None,
+        }
+    }
+}
+
+impl TrustPolicy {
+    pub fn new(
+        verify: Verify,
+        gpg_keyring: Option<PathBuf>,
+        allowed_signers: Option<PathBuf>,
+    ) -> Result<TrustPolicy> {
+        if verify != Verify::Off && gpg_keyring.is_none() && allowed_signers.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "verifying signatures needs a gpg keyring or an allowed signers file",
+            ));
+        }
+        // gpg looks up relative keyrings in its home directory, not ours
+        let absolute = |path: Option<PathBuf>| path.map(std::path::absolute).transpose();
+        Ok(TrustPolicy {
+            verify,
+            gpg_keyring: absolute(gpg_keyring)?,
+            allowed_signers: absolute(allowed_signers)?,
+        })
+    }
+
+    /// Check the signatures of the commits a change brings in, oldest first,
+    /// as the policy asks
+    pub fn check(&self, repo: &Repository, commits: &[Commit]) -> Result<()> {
+        let commits = match self.verify {
+            Verify::Off => return Ok(()),
+            Verify::Tip => match commits.last() {
+                Some(tip) => std::slice::from_ref(tip),
+                None => return Ok(()),
+            },
+            Verify::All => commits,
+        };
+        for commit in commits {
+            self.check_commit(repo, commit.id())?;
+        }
+        Ok(())
+    }
+
+    /// Check the signatures of every one of the commits, as `All` does,
+    /// unless verifying is off
+    pub fn check_every(&self, repo: &Repository, commits: &[Commit]) -> Result<()> {
+        for commit in commits {
+            self.check_commit(repo, commit.id())?;
+        }
+        Ok(())
+    }
+
+    /// Fail unless the commit is signed by a trusted key
+    pub fn check_commit(&self, repo: &Repository, oid: Oid) -> Result<()> {
+        if self.verify == Verify::Off {
+            return Ok(());
+        }
+        let untrusted = |reason: String| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("commit {} is untrusted, {}", oid, reason),
+            )
+        };
+
+        let (signature, data) = match repo.extract_signature(&oid, None) {
+            Ok((signature, data)) => (signature.to_vec(), data.to_vec()),
+            Err(_) => return Err(untrusted(String::from("it isn't signed"))),
+        };
+
+        if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") {
+            let allowed_signers = match self.allowed_signers {
+                Some(ref path) => path,
+                None => return Err(untrusted(String::from("SSH signatures aren't trusted"))),
+            };
+            verify_ssh(allowed_signers, &signature, &data).map_err(untrusted)
+        } else {
+            let keyring = match self.gpg_keyring {
+                Some(ref path) => path,
+                None => return Err(untrusted(String::from("GPG signatures aren't trusted"))),
+            };
+            verify_gpg(keyring, &signature, &data).map_err(untrusted)
+        }
+    }
+}
+
+fn verify_gpg(keyring: &Path, signature: &[u8], data: &[u8]) -> std::result::Result<(), String> {
+    let signature_file = SignatureFile::new(signature)?;
+    let mut command = Command::new("gpg");
+    command
+        .args(["--batch", "--no-default-keyring", "--keyring"])
+        .arg(keyring)
+        .args(["--status-fd", "1", "--verify"])
+        .arg(&signature_file.0)
+        .arg("-");
+    let status = run(command, data)?;
+
+    // VALIDSIG is only reported for a good signature by a key in the keyring
+    if status.lines().any(|l| l.starts_with("[GNUPG:] VALIDSIG")) {
+        Ok(())
+    } else {
+        Err(String::from(
+            "its GPG signature isn't by a key in the keyring",
+        ))
+    }
+}
+
+fn verify_ssh(
+    allowed_signers: &Path,
+    signature: &[u8],
+    data: &[u8],
+) -> std::result::Result<(), String> {
+    let signature_file = SignatureFile::new(signature)?;
+
+    // As git does, find who the key belongs to, then check they signed it
+    let mut command = Command::new("ssh-keygen");
+    command
+        .args(["-Y", "find-principals", "-f"])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(&signature_file.0);
+    let principal = match run(command, &[]) {
+        Ok(principals) => principals.lines().next().unwrap_or_default().to_string(),
+        Err(_) => String::new(),
+    };
+    if principal.is_empty() {
+        return Err(String::from(
+            "its SSH signature isn't by a key in the allowed signers",
+        ));
+    }
+
+    let mut command = Command::new("ssh-keygen");
+    command
+        .args(["-Y", "verify", "-n", "git", "-f"])
+        .arg(allowed_signers)
+        .args(["-I", &principal, "-s"])
+        .arg(&signature_file.0);
+    run(command, data)
+        .map(|_| ())
+        .map_err(|e| format!("its SSH signature doesn't verify, {}", e))
+}
+
+/// Run a command with `input` on stdin, returning its stdout when it succeeds
+fn run(mut command: Command, input: &[u8]) -> std::result::Result<String, String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run {:?}, {}", command.get_program(), e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input).map_err(|e| e.to_string())?;
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+/// A signature written out for gpg or ssh-keygen to read, removed on drop
+struct SignatureFile(PathBuf);
+
+impl SignatureFile {
+    fn new(signature: &[u8]) -> std::result::Result<SignatureFile, String> {
+        let path = std::env::temp_dir().join(format!("goa-{}.sig", Uuid::new_v4()));
+        std::fs::write(&path, signature).map_err(|e| e.to_string())?;
+        Ok(SignatureFile(path))
+    }
+}
+
+impl Drop for SignatureFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use super::*;
+    use crate::testing::{self, commit_signed, ssh_key};
+
+    #[test]
+    fn test_verify_ssh() {
+        let (upstream, _) = testing::init_upstream();
+        let (key, allowed_signers) = ssh_key();
+        let (other_key, _) = ssh_key();
+        let policy = TrustPolicy::new(Verify::Tip, None, Some(allowed_signers)).unwrap();
+
+        let unsigned = upstream.head().unwrap().target().unwrap();
+        let err = policy.check_commit(&upstream, unsigned).unwrap_err();
+        assert!(err.to_string().contains("isn't signed"));
+
+        let signed = commit_signed(&upstream, &key, "signed");
+        policy.check_commit(&upstream, signed).unwrap();
+
+        let untrusted = commit_signed(&upstream, &other_key, "signed by someone else");
+        let err = policy.check_commit(&upstream, untrusted).unwrap_err();
+        assert!(err.to_string().contains("allowed signers"));
+    }
+
+    #[test]
+    fn test_check_tip_or_all() {
+        let (upstream, _) = testing::init_upstream();
+        let (key, allowed_signers) = ssh_key();
+        testing::commit_file(&upstream, "a.txt", "a\n", "unsigned");
+        commit_signed(&upstream, &key, "signed");
+        let commits: Vec<Commit> = {
+            let head = upstream.head().unwrap().peel_to_commit().unwrap();
+            vec![head.parent(0).unwrap(), head]
+        };
+
+        let tip = TrustPolicy::new(Verify::Tip, None, Some(allowed_signers.clone())).unwrap();
+        tip.check(&upstream, &commits).unwrap();
+        let all = TrustPolicy::new(Verify::All, None, Some(allowed_signers)).unwrap();
+        assert!(all.check(&upstream, &commits).is_err());
+        TrustPolicy::default().check(&upstream, &commits).unwrap();
+    }
+
+    #[test]
+    fn test_policy_needs_keys() {
+        assert!(TrustPolicy::new(Verify::Tip, None, None).is_err());
+        assert!(TrustPolicy::new(Verify::Off, None, None).is_ok());
+        assert_eq!("all".parse(), Ok(Verify::All));
+        assert!("some".parse::<Verify>().is_err());
+    }
+}
@@ -54,6 +54,8 @@ fn test_spy_repo_command_bad_command() -> Result<(), Box<dyn std::error::Error>>
     cmd.arg("-e");
     cmd.arg("-c");
     cmd.arg("/notarealcommand");
+    cmd.arg("--failure-policy");
+    cmd.arg("exit");
     cmd.assert()
         .stderr(predicates::str::contains("/notarealcommand:"));
     cmd.assert().failure().code(127);
@@ -68,6 +70,8 @@ fn test_spy_repo_command_bad_command_in_goa_file() -> Result<(), Box<dyn std::er
     cmd.arg("-e");
     cmd.arg("-v");
     cmd.arg("3");
+    cmd.arg("--failure-policy");
+    cmd.arg("exit");
     cmd.assert()
         .stderr(predicates::str::contains("/notarealcommand:"));
     cmd.assert().failure().code(127);
@@ -107,7 +111,7 @@ fn test_spy_repo_command_good_start() -> Result<(), Box<dyn std::error::Error>>
     let repo = Repository::init(local_path).expect("Couldn't open repository");
     let mut goa_file = File::create(file_path)?;
     goa_file.write_all(b"echo \"Hello")?;
-    match add_and_commit(&repo, Path::new(".goa"), "test"){
+    match add_and_commit(&repo, Path::new(".goa"), "test") {
         Ok(oid) => println!("OID: {}", oid),
         Err(e) => eprintln!("error: {}", e),
     }
@@ -132,7 +136,7 @@ fn test_spy_repo_command_good_start() -> Result<(), Box<dyn std::error::Error>>
 
     goa_file.write_all(b", world!\"")?;
 
-    match add_and_commit(&repo, Path::new(".goa"), "test2"){
+    match add_and_commit(&repo, Path::new(".goa"), "test2") {
         Ok(oid) => println!("OID: {}", oid),
         Err(e) => eprintln!("error: {}", e),
     }
@@ -158,6 +162,33 @@ fn test_spy_repo_command_catch_diff() -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+#[test]
+fn test_run_command_missing_config() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("goa")?;
+    cmd.arg("run");
+    cmd.arg("--config");
+    cmd.arg("/blahdy/goa.toml");
+    cmd.assert().failure().stderr(predicates::str::contains(
+        "goa error: unable to read config",
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_run_command_empty_config() -> Result<(), Box<dyn std::error::Error>> {
+    let config_path = temp_dir().join(format!("{}.toml", Uuid::new_v4()));
+    std::fs::write(&config_path, "repos = []\n")?;
+
+    let mut cmd = Command::cargo_bin("goa")?;
+    cmd.arg("run");
+    cmd.arg("-c");
+    cmd.arg(&config_path);
+    cmd.assert()
+        .failure()
+        .stderr(predicates::str::contains("config has no repos to spy"));
+    Ok(())
+}
+
 #[test]
 fn test_help_command() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = Command::cargo_bin("goa")?;
@@ -184,7 +215,7 @@ use std::path::Path;
 
 use git2::{Commit, ObjectType};
 
-fn find_last_commit(repo: &Repository) -> Result<Commit, git2::Error> {
+fn find_last_commit(repo: &Repository) -> Result<Commit<'_>, git2::Error> {
     let obj = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
     obj.into_commit()
         .map_err(|_| git2::Error::from_str("Couldn't find commit"))
@@ -195,7 +226,7 @@ fn add_and_commit(repo: &Repository, path: &Path, message: &str) -> Result<Oid,
     index.add_path(path)?;
     let oid = index.write_tree()?;
     let signature = Signature::now("Kit Plummer", "kitplummer@gmail.com")?;
-    match find_last_commit(&repo) {
+    match find_last_commit(repo) {
         Ok(parent_commit) => {
             let tree = repo.find_tree(oid)?;
             repo.commit(